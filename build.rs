@@ -0,0 +1,29 @@
+use std::path::Path;
+use std::process::Command;
+
+/// Output of a command, if it ran successfully
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_string())
+}
+
+fn main() {
+    // Record what the exporter was built with for padm_exporter_build_info
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| String::from("rustc"));
+    let rustc_version = command_output(&rustc, &["--version"])
+        .and_then(|v| v.split_whitespace().nth(1).map(|v| v.to_string()))
+        .unwrap_or_else(|| String::from("unknown"));
+    let commit = command_output("git", &["rev-parse", "--short", "HEAD"])
+        .unwrap_or_else(|| String::from("unknown"));
+    println!("cargo:rustc-env=PADM_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=PADM_GIT_COMMIT={}", commit);
+
+    if Path::new(".git/HEAD").exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        println!("cargo:rerun-if-changed=.git/refs");
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -1,12 +1,53 @@
-use serde::Deserialize;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::HashMap;
 use std::fs;
 
+use crate::padm_client::variables::{known_metric_names, register_extra_metrics, Variable};
+
+/// Where variables are found in a response unless an endpoint says otherwise
+pub const DEFAULT_DATA_POINTER: &str = "/data";
+pub const DEFAULT_ATTRIBUTES_KEY: &str = "attributes";
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     ip: String,
     port: Option<u16>,
+    metrics_port: Option<u16>,
     log_level: Option<String>,
+    empty_as_204: Option<bool>,
+    scrape_timeout: Option<u64>,
+    label_transforms: Option<Vec<LabelTransform>>,
+    extra_metrics: Option<Vec<ExtraMetric>>,
+    unparseable_as_nan: Option<bool>,
+    probe_summary_log: Option<bool>,
+    pushgateway_url: Option<String>,
+    pushgateway_job: Option<String>,
+    pushgateway_instance: Option<String>,
+    pushgateway_interval: Option<u64>,
+    pushgateway_timeout: Option<u64>,
+    metric_scales: Option<HashMap<String, f64>>,
+    emit_legacy_aliases: Option<bool>,
+    disable_metrics: Option<Vec<String>>,
+    emit_metric_metadata: Option<bool>,
+    emit_device_type_label: Option<bool>,
+    metric_prefix: Option<String>,
+    help_overrides: Option<HashMap<String, String>>,
+    enable_debug_endpoints: Option<bool>,
+    connection_close: Option<bool>,
+    si_base_units: Option<bool>,
+    admin_token: Option<String>,
+    counter_reset_protection: Option<bool>,
+    suppress_maintenance_metrics: Option<bool>,
+    maintenance_indicator: Option<MaintenanceIndicator>,
+    scrape_duration_buckets: Option<Vec<f64>>,
+    timestamp_policy: Option<TimestampPolicy>,
+    on_duplicate_target: Option<DuplicateTargetPolicy>,
+    na_values: Option<Vec<String>>,
+    na_policy: Option<NaPolicy>,
     endpoints: Vec<Endpoint>,
+    #[serde(skip)]
+    load_warnings: Vec<String>,
 }
 impl Config {
     pub fn ip(&self) -> &str {
@@ -21,24 +62,323 @@ impl Config {
             None => "info"
         }
     }
+    pub fn empty_as_204(&self) -> bool {
+        self.empty_as_204.unwrap_or(false)
+    }
+    /// Seconds a scrape waits for the first probe results, defaulting to a
+    /// little over the longest endpoint interval
+    pub fn scrape_timeout(&self) -> u64 {
+        self.scrape_timeout.unwrap_or_else(|| {
+            self.endpoints
+                .iter()
+                .map(|e| e.interval())
+                .max()
+                .unwrap_or(0)
+                + 5
+        })
+    }
+    pub fn label_transforms(&self) -> &[LabelTransform] {
+        match &self.label_transforms {
+            Some(t) => t,
+            None => &[],
+        }
+    }
+    /// Additional device labels to expose as metrics
+    pub fn extra_metrics(&self) -> &[ExtraMetric] {
+        match &self.extra_metrics {
+            Some(m) => m,
+            None => &[],
+        }
+    }
+    /// Map device labels to the extra metrics, replacing those of any previous
+    /// config and returning a warning for each mapping they replace
+    pub fn register_extra_metrics(&self) -> Vec<String> {
+        register_extra_metrics(self.extra_metrics())
+            .iter()
+            .map(|m| {
+                format!(
+                    "Extra metric '{}' replaces the mapping of '{}'",
+                    m.name(),
+                    m.label()
+                )
+            })
+            .collect()
+    }
+    pub fn unparseable_as_nan(&self) -> bool {
+        self.unparseable_as_nan.unwrap_or(false)
+    }
+    pub fn probe_summary_log(&self) -> bool {
+        self.probe_summary_log.unwrap_or(false)
+    }
+    pub fn pushgateway_url(&self) -> Option<&str> {
+        self.pushgateway_url.as_deref()
+    }
+    pub fn pushgateway_job(&self) -> &str {
+        match &self.pushgateway_job {
+            Some(s) => s,
+            None => "padm_exporter",
+        }
+    }
+    pub fn pushgateway_instance(&self) -> Option<&str> {
+        self.pushgateway_instance.as_deref()
+    }
+    /// Minimum number of seconds between pushes
+    pub fn pushgateway_interval(&self) -> u64 {
+        self.pushgateway_interval.unwrap_or(0)
+    }
+    /// Seconds to wait for the Pushgateway before giving up on a push
+    pub fn pushgateway_timeout(&self) -> u64 {
+        self.pushgateway_timeout.unwrap_or(10)
+    }
+    /// Factor to multiply the values of a metric by, if configured
+    pub fn metric_scale(&self, name: &str) -> Option<f64> {
+        self.metric_scales.as_ref()?.get(name).copied()
+    }
+    /// Whether to also emit renamed metrics under their previous names
+    pub fn emit_legacy_aliases(&self) -> bool {
+        self.emit_legacy_aliases.unwrap_or(false)
+    }
+    /// Whether a metric has been disabled for all endpoints
+    pub fn metric_disabled(&self, name: &str) -> bool {
+        match &self.disable_metrics {
+            Some(names) => names.iter().any(|n| n == name),
+            None => false,
+        }
+    }
+    pub fn emit_metric_metadata(&self) -> bool {
+        self.emit_metric_metadata.unwrap_or(false)
+    }
+    /// Whether to label device metrics with the device type, e.g. to group by model
+    pub fn emit_device_type_label(&self) -> bool {
+        self.emit_device_type_label.unwrap_or(false)
+    }
+    /// Prefix prepended to the names of device metrics
+    pub fn metric_prefix(&self) -> &str {
+        self.metric_prefix.as_deref().unwrap_or("padm_")
+    }
+    /// Help text for a metric family, with `{name}`, `{type}` and `{unit}` filled in
+    pub fn help_override(&self, name: &str, mtype: &str, unit: &str) -> Option<String> {
+        let template = self.help_overrides.as_ref()?.get(name)?;
+        let full_name = format!("{}{}", self.metric_prefix(), name);
+        Some(
+            template
+                .replace("{name}", full_name.as_str())
+                .replace("{type}", mtype)
+                .replace("{unit}", unit),
+        )
+    }
+    /// Whether to serve debugging endpoints such as the CPU profiler
+    pub fn enable_debug_endpoints(&self) -> bool {
+        self.enable_debug_endpoints.unwrap_or(false)
+    }
+    /// Whether to close the connection after each scrape response
+    pub fn connection_close(&self) -> bool {
+        self.connection_close.unwrap_or(false)
+    }
+    /// Whether to convert temperatures to kelvin and energy to joules
+    pub fn si_base_units(&self) -> bool {
+        self.si_base_units.unwrap_or(false)
+    }
+    /// Bearer token required by the admin endpoints, which are disabled without one
+    pub fn admin_token(&self) -> Option<&str> {
+        self.admin_token.as_deref()
+    }
+    /// Whether to keep counters monotonic across device resets
+    pub fn counter_reset_protection(&self) -> bool {
+        self.counter_reset_protection.unwrap_or(false)
+    }
+    /// Variable that flags a device as being in maintenance, if its metrics should be suppressed
+    pub fn maintenance_indicator(&self) -> Option<&MaintenanceIndicator> {
+        match self.suppress_maintenance_metrics.unwrap_or(false) {
+            true => self.maintenance_indicator.as_ref(),
+            false => None,
+        }
+    }
+    /// Upper bounds of the buckets of the scrape duration histogram
+    pub fn scrape_duration_buckets(&self) -> &[f64] {
+        match &self.scrape_duration_buckets {
+            Some(b) => b,
+            None => &[
+                0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+            ],
+        }
+    }
+    pub fn timestamp_policy(&self) -> TimestampPolicy {
+        self.timestamp_policy.unwrap_or_default()
+    }
+    /// How to emit a value that is one of the configured not-available sentinels,
+    /// if it should be treated differently from other unparseable values
+    pub fn na_policy(&self, value: &str) -> Option<NaPolicy> {
+        let policy = self.na_policy?;
+        let sentinel = match &self.na_values {
+            Some(values) => values.iter().any(|v| v == value.trim()),
+            None => ["N/A", "---", "Not Available"].contains(&value.trim()),
+        };
+        sentinel.then_some(policy)
+    }
+    /// Problems that were corrected while loading the config
+    pub fn load_warnings(&self) -> &[String] {
+        &self.load_warnings
+    }
     pub fn endpoints(&self) -> &Vec<Endpoint> {
         &self.endpoints
     }
+    /// Check for settings that would leave the exporter silently broken,
+    /// listing every problem found
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = Vec::new();
+        if self.endpoints.is_empty() {
+            problems.push(String::from("No endpoints are configured"));
+        }
+        for (i, endpoint) in self.endpoints.iter().enumerate() {
+            let host = endpoint.host();
+            if !valid_address(endpoint.ip()) {
+                problems.push(format!("Endpoint {} has an invalid ip", host));
+            }
+            if endpoint.port() == 0 {
+                problems.push(format!("Endpoint {} has an invalid port 0", host));
+            }
+            let credentials = match endpoint.grant_type() {
+                GrantType::Password => [
+                    ("username", &endpoint.username),
+                    ("password", &endpoint.password),
+                ],
+                GrantType::ClientCredentials => [
+                    ("client_id", &endpoint.client_id),
+                    ("client_secret", &endpoint.client_secret),
+                ],
+            };
+            for (what, value) in credentials {
+                match value.as_deref() {
+                    None => problems.push(format!("Endpoint {} has no {}", host, what)),
+                    Some("") => problems.push(format!("Endpoint {} has an empty {}", host, what)),
+                    Some(_) => (),
+                }
+            }
+            // Refuse to send credentials in plaintext unless explicitly allowed
+            if endpoint.scheme() == "http" && !endpoint.allow_insecure_http() {
+                problems.push(format!(
+                    "Endpoint {} uses http without allow_insecure_http = true",
+                    host
+                ));
+            }
+            if let Err(e) = endpoint.resolve_overrides() {
+                problems.push(format!("Endpoint {}: {}", host, e));
+            }
+            // Duplicates are only left in place when on_duplicate_target is error,
+            // report each of them once at its second occurrence
+            if self.endpoints[..i]
+                .iter()
+                .filter(|e| e.host() == host)
+                .count()
+                == 1
+            {
+                problems.push(format!("Endpoint {} is configured more than once", host));
+            }
+        }
+
+        // HELP is per metric family, so only family-level placeholders make sense
+        if let Some(overrides) = &self.help_overrides {
+            for (name, template) in overrides {
+                for placeholder in template
+                    .split('{')
+                    .skip(1)
+                    .filter_map(|p| p.split_once('}'))
+                {
+                    if !HELP_PLACEHOLDERS.contains(&placeholder.0) {
+                        problems.push(format!(
+                            "Help override for '{}' uses unsupported placeholder '{{{}}}', expected one of {{name}}, {{type}}, {{unit}}",
+                            name, placeholder.0
+                        ));
+                    }
+                }
+            }
+        }
+        // Metric names must stay valid once prefixed
+        let prefix = self.metric_prefix();
+        let valid_start = |c: char| c.is_ascii_alphabetic() || c == '_' || c == ':';
+        if !prefix.starts_with(valid_start)
+            || !prefix.chars().all(|c| valid_start(c) || c.is_ascii_digit())
+        {
+            problems.push(format!(
+                "Invalid metric_prefix '{}', expected to match [a-zA-Z_:][a-zA-Z0-9_:]*",
+                prefix
+            ));
+        }
+        match problems.is_empty() {
+            true => Ok(()),
+            false => Err(problems
+                .iter()
+                .map(|p| format!("  - {}", p))
+                .collect::<Vec<_>>()
+                .join("\n")),
+        }
+    }
 
     pub fn bind_address(&self) -> String {
-        format!("{}:{}", self.ip(), self.port())
+        authority(self.ip(), self.port())
+    }
+    /// Address for a separate exporter metrics listener, if configured
+    pub fn metrics_bind_address(&self) -> Option<String> {
+        self.metrics_port.map(|port| authority(self.ip(), port))
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Endpoint {
     ip: String,
     port: Option<u16>,
     scheme: Option<String>,
     tls_insecure: Option<bool>,
     interval: Option<u64>,
-    username: String,
-    password: String,
+    emit_instance_label: Option<bool>,
+    emit_target_label: Option<bool>,
+    instance: Option<String>,
+    success_predicate: Option<SuccessPredicate>,
+    dns_retries: Option<u32>,
+    dns_retry_delay_ms: Option<u64>,
+    #[serde(default, deserialize_with = "deserialize_tls_version")]
+    min_tls_version: Option<reqwest::tls::Version>,
+    client_identity_p12: Option<String>,
+    ca_cert: Option<String>,
+    client_cert: Option<String>,
+    client_key: Option<String>,
+    client_identity_password: Option<String>,
+    auth_failure_threshold: Option<u32>,
+    auth_failure_backoff: Option<u64>,
+    max_api_response_bytes: Option<usize>,
+    single_device_mode: Option<bool>,
+    auth_rate_limit: Option<u32>,
+    auth_rate_window: Option<u64>,
+    stale_grace_secs: Option<u64>,
+    allow_insecure_http: Option<bool>,
+    error_field: Option<String>,
+    rebuild_after_errors: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_probe_method")]
+    probe_method: Option<reqwest::Method>,
+    probe_body: Option<String>,
+    max_pages: Option<u32>,
+    expected_devices: Option<usize>,
+    data_pointer: Option<String>,
+    attributes_key: Option<String>,
+    warmup_probes: Option<u32>,
+    resolve: Option<Vec<String>>,
+    connect_host: Option<String>,
+    connect_port: Option<u16>,
+    retry_budget: Option<u32>,
+    proxy: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    timeout: Option<u64>,
+    username: Option<String>,
+    username_file: Option<String>,
+    password: Option<String>,
+    password_file: Option<String>,
+    grant_type: Option<GrantType>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    client_secret_file: Option<String>,
+    include_devices: Option<Vec<String>>,
+    exclude_devices: Option<Vec<String>>,
 }
 impl Endpoint {
     pub fn ip(&self) -> &str {
@@ -59,20 +399,1050 @@ impl Endpoint {
     pub fn interval(&self) -> u64 {
         self.interval.unwrap_or(30)
     }
+    /// Seconds to wait for a response from the device before giving up
+    pub fn timeout(&self) -> u64 {
+        self.timeout.unwrap_or(10)
+    }
+    pub fn emit_instance_label(&self) -> bool {
+        self.emit_instance_label.unwrap_or(false)
+    }
+    /// Whether to label device metrics with the endpoint host, keeping devices of
+    /// the same name behind different endpoints apart
+    pub fn emit_target_label(&self) -> bool {
+        self.emit_target_label.unwrap_or(true)
+    }
+    /// Value of the `instance` label, defaulting to the endpoint host
+    pub fn instance(&self) -> String {
+        match &self.instance {
+            Some(s) => s.to_owned(),
+            None => self.host(),
+        }
+    }
+    pub fn success_predicate(&self) -> Option<&SuccessPredicate> {
+        self.success_predicate.as_ref()
+    }
+    pub fn dns_retries(&self) -> u32 {
+        self.dns_retries.unwrap_or(2)
+    }
+    pub fn dns_retry_delay_ms(&self) -> u64 {
+        self.dns_retry_delay_ms.unwrap_or(1000)
+    }
+    pub fn min_tls_version(&self) -> Option<reqwest::tls::Version> {
+        self.min_tls_version
+    }
+    /// PEM file with an additional CA certificate to trust
+    pub fn ca_cert(&self) -> Option<&str> {
+        self.ca_cert.as_deref()
+    }
+    /// PEM client certificate, paired with `client_key`
+    pub fn client_cert(&self) -> Option<&str> {
+        self.client_cert.as_deref()
+    }
+    /// PEM PKCS#8 private key of `client_cert`
+    pub fn client_key(&self) -> Option<&str> {
+        self.client_key.as_deref()
+    }
+    pub fn client_identity_p12(&self) -> Option<&str> {
+        self.client_identity_p12.as_deref()
+    }
+    pub fn client_identity_password(&self) -> &str {
+        match &self.client_identity_password {
+            Some(s) => s,
+            None => "",
+        }
+    }
+    pub fn has_client_identity_password(&self) -> bool {
+        self.client_identity_password.is_some()
+    }
+    pub fn auth_failure_threshold(&self) -> u32 {
+        self.auth_failure_threshold.unwrap_or(3)
+    }
+    pub fn auth_failure_backoff(&self) -> u64 {
+        self.auth_failure_backoff.unwrap_or(3600)
+    }
+    pub fn max_api_response_bytes(&self) -> usize {
+        self.max_api_response_bytes.unwrap_or(16 * 1024 * 1024)
+    }
+    pub fn single_device_mode(&self) -> bool {
+        self.single_device_mode.unwrap_or(false)
+    }
+    pub fn auth_rate_limit(&self) -> Option<u32> {
+        self.auth_rate_limit
+    }
+    pub fn auth_rate_window(&self) -> u64 {
+        self.auth_rate_window.unwrap_or(60)
+    }
+    /// How long past a missed probe to keep serving the last known metrics
+    pub fn stale_grace_secs(&self) -> u64 {
+        self.stale_grace_secs.unwrap_or(self.interval())
+    }
+    pub fn allow_insecure_http(&self) -> bool {
+        self.allow_insecure_http.unwrap_or(false)
+    }
+    /// Top-level response field that signals an error despite a 200 status
+    pub fn error_field(&self) -> &str {
+        match &self.error_field {
+            Some(s) => s,
+            None => "error",
+        }
+    }
+    /// Number of identical consecutive connection errors before rebuilding the client
+    pub fn rebuild_after_errors(&self) -> u32 {
+        self.rebuild_after_errors.unwrap_or(5)
+    }
+    /// Retries a probe cycle may spend across DNS, authentication and redirects
+    pub fn retry_budget(&self) -> Option<u32> {
+        self.retry_budget
+    }
+    /// Proxy URL to send all requests to the device through
+    pub fn proxy(&self) -> Option<&str> {
+        self.proxy.as_deref()
+    }
+    /// Whether to export a device, by glob patterns on its name; exclusions win
+    pub fn device_wanted(&self, name: &str) -> bool {
+        let matches = |patterns: &Vec<String>| patterns.iter().any(|p| glob_match(p, name));
+        self.include_devices.as_ref().is_none_or(matches)
+            && !self.exclude_devices.as_ref().is_some_and(matches)
+    }
+    /// Extra headers sent with every request, e.g. for an API gateway
+    pub fn headers(&self) -> Option<&HashMap<String, String>> {
+        self.headers.as_ref()
+    }
+    /// HTTP method used to list variables
+    pub fn probe_method(&self) -> reqwest::Method {
+        self.probe_method.clone().unwrap_or(reqwest::Method::GET)
+    }
+    /// JSON body sent along with the variables request
+    pub fn probe_body(&self) -> Option<&str> {
+        self.probe_body.as_deref()
+    }
+    /// Maximum number of variable pages to fetch in a single probe
+    pub fn max_pages(&self) -> u32 {
+        self.max_pages.unwrap_or(100)
+    }
+    /// Number of devices the endpoint should always report
+    pub fn expected_devices(&self) -> Option<usize> {
+        self.expected_devices
+    }
+    /// JSON pointer to the list of variables in a response
+    pub fn data_pointer(&self) -> &str {
+        match &self.data_pointer {
+            Some(s) => s,
+            None => DEFAULT_DATA_POINTER,
+        }
+    }
+    /// Key, or JSON pointer, of the variable attributes within each list item
+    pub fn attributes_key(&self) -> &str {
+        match &self.attributes_key {
+            Some(s) => s,
+            None => DEFAULT_ATTRIBUTES_KEY,
+        }
+    }
+    /// Number of successful probes before the endpoint counts towards readiness
+    pub fn warmup_probes(&self) -> u32 {
+        self.warmup_probes.unwrap_or(1)
+    }
+    /// Host names to resolve to fixed addresses instead of using DNS
+    pub fn resolve_overrides(&self) -> Result<Vec<(String, std::net::IpAddr)>, String> {
+        self.resolve
+            .iter()
+            .flatten()
+            .map(|entry| {
+                let (host, ip) = entry.split_once(':').ok_or_else(|| {
+                    format!("Invalid resolve entry '{}', expected host:ip", entry)
+                })?;
+                let ip = ip
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse()
+                    .map_err(|e| format!("Invalid address in resolve entry '{}': {}", entry, e))?;
+                Ok((host.to_string(), ip))
+            })
+            .collect()
+    }
+    /// Address to actually connect to, e.g. the local end of an SSH port forward
+    pub fn connect_address(&self) -> Option<(&str, u16)> {
+        if self.connect_host.is_none() && self.connect_port.is_none() {
+            return None;
+        }
+        Some((
+            self.connect_host.as_deref().unwrap_or(self.ip()),
+            self.connect_port.unwrap_or(self.port()),
+        ))
+    }
     pub fn username(&self) -> &str {
-        &self.username
+        self.username.as_deref().unwrap_or_default()
     }
     pub fn password(&self) -> &str {
-        &self.password
+        self.password.as_deref().unwrap_or_default()
+    }
+    /// OAuth grant used to obtain an access token
+    pub fn grant_type(&self) -> GrantType {
+        self.grant_type.unwrap_or_default()
+    }
+    pub fn client_id(&self) -> &str {
+        self.client_id.as_deref().unwrap_or_default()
+    }
+    pub fn client_secret(&self) -> &str {
+        self.client_secret.as_deref().unwrap_or_default()
     }
 
     pub fn host(&self) -> String {
-        format!("{}:{}", self.ip(), self.port())
+        authority(self.ip(), self.port())
+    }
+    /// Credentials as currently found in username_file and password_file,
+    /// falling back to the loaded values for those not kept in a file
+    pub fn reread_credentials(&self) -> Result<(String, String), std::io::Error> {
+        let host = self.host();
+        if self.username_file.is_none() && self.password_file.is_none() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Endpoint {} doesn't read its credentials from files", host),
+            ));
+        }
+        let mut username = None;
+        read_secret(&mut username, &self.username_file, "username", &host)?;
+        let mut password = None;
+        read_secret(&mut password, &self.password_file, "password", &host)?;
+        Ok((
+            username.unwrap_or_else(|| self.username().to_string()),
+            password.unwrap_or_else(|| self.password().to_string()),
+        ))
+    }
+}
+
+/// Join an address and a port, bracketing IPv6 addresses that aren't already
+fn authority(ip: &str, port: u16) -> String {
+    match ip.contains(':') && !ip.starts_with('[') {
+        true => format!("[{}]:{}", ip, port),
+        false => format!("{}:{}", ip, port),
+    }
+}
+
+/// Match `text` against a glob where `*` matches any run of characters and `?` any one
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    p = star + 1;
+                    t = matched + 1;
+                    backtrack = Some((star, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// OAuth grant an endpoint authenticates with
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GrantType {
+    /// Log in with `username` and `password`
+    #[default]
+    Password,
+    /// Log in with service credentials from `client_id` and `client_secret`
+    ClientCredentials,
+}
+impl GrantType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GrantType::Password => "password",
+            GrantType::ClientCredentials => "client_credentials",
+        }
+    }
+}
+
+/// Type of a user-defined metric
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricType {
+    #[default]
+    Gauge,
+    Counter,
+}
+impl MetricType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MetricType::Gauge => "gauge",
+            MetricType::Counter => "counter",
+        }
+    }
+}
+
+/// Metric for a device label the built-in variable map doesn't know about
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExtraMetric {
+    label: String,
+    name: String,
+    help: String,
+    #[serde(default, rename = "type")]
+    mtype: MetricType,
+    #[serde(default, rename = "enum")]
+    is_enum: bool,
+    unit: Option<String>,
+}
+impl ExtraMetric {
+    /// Label of the variable as reported by the device
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    pub fn help(&self) -> &str {
+        &self.help
+    }
+    pub fn mtype(&self) -> MetricType {
+        self.mtype
+    }
+    /// Whether the value is text to expose as a `state` label instead of a number
+    pub fn is_enum(&self) -> bool {
+        self.is_enum
+    }
+    /// Unit suffix to strip from values
+    pub fn unit(&self) -> Option<&str> {
+        self.unit.as_deref()
     }
 }
 
+/// Regex replacement applied to the values of a given label
+#[derive(Debug, Clone, Deserialize)]
+pub struct LabelTransform {
+    on: String,
+    #[serde(deserialize_with = "deserialize_regex")]
+    pattern: Regex,
+    replacement: String,
+}
+impl LabelTransform {
+    /// Transform `value` if this applies to `label`
+    pub fn apply(&self, label: &str, value: &str) -> String {
+        if label != self.on {
+            return value.to_string();
+        }
+        self.pattern
+            .replace_all(value, self.replacement.as_str())
+            .into_owned()
+    }
+}
+
+fn deserialize_regex<'de, D>(deserializer: D) -> Result<Regex, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_tls_version<'de, D>(
+    deserializer: D,
+) -> Result<Option<reqwest::tls::Version>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let version = String::deserialize(deserializer)?;
+    match version.as_str() {
+        "1.0" => Ok(Some(reqwest::tls::Version::TLS_1_0)),
+        "1.1" => Ok(Some(reqwest::tls::Version::TLS_1_1)),
+        "1.2" => Ok(Some(reqwest::tls::Version::TLS_1_2)),
+        "1.3" => Ok(Some(reqwest::tls::Version::TLS_1_3)),
+        v => Err(serde::de::Error::custom(format!(
+            "invalid TLS version '{}', expected one of 1.0, 1.1, 1.2, 1.3",
+            v
+        ))),
+    }
+}
+
+fn deserialize_probe_method<'de, D>(deserializer: D) -> Result<Option<reqwest::Method>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let method = String::deserialize(deserializer)?;
+    match method.to_ascii_uppercase().as_str() {
+        "GET" => Ok(Some(reqwest::Method::GET)),
+        "POST" => Ok(Some(reqwest::Method::POST)),
+        m => Err(serde::de::Error::custom(format!(
+            "invalid probe method '{}', expected one of GET, POST",
+            m
+        ))),
+    }
+}
+
+/// Condition an API response must satisfy to count as a successful probe
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct SuccessPredicate {
+    pointer: String,
+    equals: Option<String>,
+}
+impl SuccessPredicate {
+    /// Check the value at `pointer` equals `equals`, or is non-empty if unset
+    pub fn matches(&self, json: &serde_json::Value) -> bool {
+        let value = match json.pointer(&self.pointer) {
+            Some(v) => v,
+            None => return false,
+        };
+        match &self.equals {
+            Some(expected) => match value.as_str() {
+                Some(s) => s == expected,
+                None => serde_json::from_str::<serde_json::Value>(expected)
+                    .map(|e| e == *value)
+                    .unwrap_or(false),
+            },
+            None => match value {
+                serde_json::Value::Null => false,
+                serde_json::Value::Array(a) => !a.is_empty(),
+                serde_json::Value::Object(o) => !o.is_empty(),
+                serde_json::Value::String(s) => !s.is_empty(),
+                _ => true,
+            },
+        }
+    }
+}
+
+/// Which timestamp, if any, to attach to device samples
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampPolicy {
+    /// Leave it to Prometheus to stamp samples at scrape time
+    #[default]
+    None,
+    /// Use the time reported by the device, where there is one
+    Device,
+    /// Use the time the exporter fetched the values from the device
+    Scrape,
+}
+
+/// How to emit values a device reports for sensors it doesn't support
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NaPolicy {
+    Skip,
+    Zero,
+    Nan,
+}
+
+/// What to do when several endpoints share the same host and port
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateTargetPolicy {
+    /// Refuse to load the config
+    #[default]
+    Error,
+    /// Keep the first endpoint in the file
+    First,
+    /// Keep the last endpoint in the file
+    Last,
+}
+
+/// Metric whose value, or the value of one of its labels, marks a device as in maintenance
+#[derive(Debug, Clone, Deserialize)]
+pub struct MaintenanceIndicator {
+    metric: String,
+    label: Option<String>,
+    equals: String,
+}
+impl MaintenanceIndicator {
+    pub fn matches(&self, variable: &Variable) -> bool {
+        if variable.get("name") != self.metric {
+            return false;
+        }
+        match &self.label {
+            Some(label) => variable
+                .labels()
+                .as_ref()
+                .and_then(|l| l.get(label))
+                .is_some_and(|v| *v == self.equals),
+            None => variable.get("value") == self.equals,
+        }
+    }
+}
+
+/// Placeholders allowed in help overrides
+const HELP_PLACEHOLDERS: [&str; 3] = ["name", "type", "unit"];
+
+/// Prefix of the environment variables overriding the credentials of an endpoint,
+/// e.g. `PADM_TARGET_10_0_0_100_443` for `10.0.0.100:443`, followed by
+/// `_USERNAME` or `_PASSWORD`
+fn env_prefix(host: &str) -> String {
+    let host: String = host
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect();
+    format!("PADM_TARGET_{}", host)
+}
+
+/// Whether an address is an IP address, optionally bracketed or scoped, or a host name
+fn valid_address(address: &str) -> bool {
+    let unbracketed = address.trim_start_matches('[').trim_end_matches(']');
+    let unscoped = unbracketed.split('%').next().unwrap_or_default();
+    if unscoped.parse::<std::net::IpAddr>().is_ok() {
+        return true;
+    }
+    !address.is_empty()
+        && address.split('.').all(|part| {
+            !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Substitute `${NAME}` with the value of environment variables, `$${` escaping a literal `${`
+fn interpolate_env(text: &str) -> Result<String, std::io::Error> {
+    let mut output = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(i) = rest.find('$') {
+        output.push_str(&rest[..i]);
+        rest = &rest[i..];
+        if let Some(after) = rest.strip_prefix("$${") {
+            output.push_str("${");
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix("${") {
+            let end = after.find('}').ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Unterminated ${ in config, use $${ for a literal ${",
+                )
+            })?;
+            let name = &after[..end];
+            let value = std::env::var(name).map_err(|_| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Environment variable {} used in config is not set", name),
+                )
+            })?;
+            output.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            output.push('$');
+            rest = &rest[1..];
+        }
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+/// Interpolate environment variables into every string of a parsed config
+fn interpolate_env_values(value: &mut toml::Value) -> Result<(), std::io::Error> {
+    match value {
+        toml::Value::String(s) => *s = interpolate_env(s)?,
+        toml::Value::Array(values) => {
+            for value in values {
+                interpolate_env_values(value)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                interpolate_env_values(value)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Fill in a credential from `file` if given, refusing to have it set both ways
+fn read_secret(
+    value: &mut Option<String>,
+    file: &Option<String>,
+    what: &str,
+    host: &str,
+) -> Result<(), std::io::Error> {
+    let path = match file {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    if value.is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Endpoint {} sets both {} and {}_file, only one is allowed",
+                host, what, what
+            ),
+        ));
+    }
+    let secret = fs::read_to_string(path).map_err(|e| {
+        std::io::Error::new(
+            e.kind(),
+            format!(
+                "Failed reading {} of endpoint {} from {}: {}",
+                what, host, path, e
+            ),
+        )
+    })?;
+    *value = Some(secret.trim().to_string());
+    Ok(())
+}
+
+/// Trim surrounding whitespace in place, returning whether anything was removed
+fn trim_field(field: &mut String) -> bool {
+    let trimmed = field.trim();
+    if trimmed.len() == field.len() {
+        return false;
+    }
+    *field = trimmed.to_string();
+    true
+}
+
 pub fn load_config_from_file(file_path: &str) -> Result<Config, std::io::Error> {
-    let config: Config =
-        toml::from_str(&fs::read_to_string(file_path)?).expect("Failed parsing toml config");
+    // Substitute into parsed values so that secrets need no TOML escaping and
+    // comments are left alone
+    let mut value: toml::Value = toml::from_str(&fs::read_to_string(file_path)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    interpolate_env_values(&mut value)?;
+    let mut config: Config = value
+        .try_into()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+
+    // Strip whitespace pasted along with hosts and credentials; passwords may
+    // legitimately contain spaces so they are left untouched
+    let mut warnings = Vec::new();
+    if trim_field(&mut config.ip) {
+        warnings.push(String::from("Trimmed whitespace from 'ip'"));
+    }
+    for endpoint in &mut config.endpoints {
+        if trim_field(&mut endpoint.ip) {
+            warnings.push(format!(
+                "Trimmed whitespace from endpoint ip '{}'",
+                endpoint.ip
+            ));
+        }
+        if let Some(username) = &mut endpoint.username {
+            if trim_field(username) {
+                warnings.push(format!(
+                    "Trimmed whitespace from username of endpoint {}",
+                    endpoint.ip
+                ));
+            }
+        }
+
+        // Read credentials kept in separate files, e.g. mounted secrets
+        let host = endpoint.host();
+        read_secret(
+            &mut endpoint.username,
+            &endpoint.username_file,
+            "username",
+            &host,
+        )?;
+        read_secret(
+            &mut endpoint.password,
+            &endpoint.password_file,
+            "password",
+            &host,
+        )?;
+        read_secret(
+            &mut endpoint.client_secret,
+            &endpoint.client_secret_file,
+            "client_secret",
+            &host,
+        )?;
+        if let Some(scheme) = &mut endpoint.scheme {
+            if trim_field(scheme) {
+                warnings.push(format!(
+                    "Trimmed whitespace from scheme of endpoint {}",
+                    endpoint.ip
+                ));
+            }
+        }
+        // Zoned addresses are reached through a placeholder host name, which
+        // no device certificate will be issued for
+        if endpoint.ip.contains('%') && endpoint.scheme() == "https" && !endpoint.tls_insecure() {
+            warnings.push(format!(
+                "Endpoint {} has a link-local address with a zone, its certificate can't be verified without tls_insecure",
+                endpoint.ip
+            ));
+        }
+
+        // Let secrets be injected through the environment instead of the file
+        let prefix = env_prefix(&endpoint.host());
+        if let Ok(username) = std::env::var(format!("{}_USERNAME", prefix)) {
+            endpoint.username = Some(username);
+        }
+        if let Ok(password) = std::env::var(format!("{}_PASSWORD", prefix)) {
+            endpoint.password = Some(password);
+        }
+        if let Ok(client_secret) = std::env::var(format!("{}_CLIENT_SECRET", prefix)) {
+            endpoint.client_secret = Some(client_secret);
+        }
+    }
+    // Metrics of endpoints sharing a host would collide, so keep only one of them
+    let policy = config.on_duplicate_target.unwrap_or_default();
+    let mut hosts: Vec<String> = Vec::new();
+    let mut keep: Vec<Endpoint> = Vec::new();
+    for endpoint in config.endpoints.drain(..) {
+        let host = endpoint.host();
+        match hosts.iter().position(|h| *h == host) {
+            None => {
+                hosts.push(host);
+                keep.push(endpoint);
+            }
+            Some(i) => match policy {
+                // Left for validate() to report along with any other problems
+                DuplicateTargetPolicy::Error => keep.push(endpoint),
+                DuplicateTargetPolicy::First => {
+                    warnings.push(format!("Ignoring duplicate endpoint {}", host));
+                }
+                DuplicateTargetPolicy::Last => {
+                    warnings.push(format!("Replacing earlier endpoint {}", host));
+                    keep[i] = endpoint;
+                }
+            },
+        }
+    }
+    config.endpoints = keep;
+
+    if let Some(names) = &config.disable_metrics {
+        let mut known = known_metric_names();
+        known.extend(config.extra_metrics().iter().map(|m| m.name().to_string()));
+        for name in names.iter().filter(|n| !known.contains(n)) {
+            warnings.push(format!("Unknown metric '{}' in disable_metrics", name));
+        }
+    }
+    if config.suppress_maintenance_metrics.unwrap_or(false)
+        && config.maintenance_indicator.is_none()
+    {
+        warnings.push(String::from(
+            "suppress_maintenance_metrics is set without maintenance_indicator, nothing will be suppressed",
+        ));
+    }
+    config.load_warnings = warnings;
+
     Ok(config)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn endpoint(settings: &str) -> Endpoint {
+        toml::from_str(&format!("ip = \"192.0.2.1\"\n{}", settings)).unwrap()
+    }
+
+    #[test]
+    fn joins_authorities() {
+        assert_eq!(authority("192.0.2.1", 443), "192.0.2.1:443");
+        assert_eq!(authority("padm.example.com", 8443), "padm.example.com:8443");
+        assert_eq!(authority("2001:db8::1", 443), "[2001:db8::1]:443");
+        assert_eq!(authority("[2001:db8::1]", 443), "[2001:db8::1]:443");
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("*-pdu", "rack1-pdu"));
+        assert!(glob_match("*-pdu", "-pdu"));
+        assert!(!glob_match("*-pdu", "rack1-pdu2"));
+        assert!(glob_match("rack?-*", "rack1-cooling"));
+        assert!(!glob_match("rack?-*", "rack12-cooling"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(!glob_match("exact", "exactly"));
+    }
+
+    #[test]
+    fn filters_devices() {
+        let all = endpoint("");
+        assert!(all.device_wanted("cool-1"));
+
+        let pdus = endpoint("include_devices = [\"*-pdu\"]\nexclude_devices = [\"spare-pdu\"]");
+        assert!(pdus.device_wanted("rack1-pdu"));
+        assert!(!pdus.device_wanted("rack1-cooling"));
+        // Exclusions win over inclusions
+        assert!(!pdus.device_wanted("spare-pdu"));
+    }
+
+    /// Load `text` as a config file named after the calling test
+    fn load(name: &str, text: &str) -> Result<Config, std::io::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "padm_exporter_{}_{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, text).unwrap();
+        let config = load_config_from_file(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn interpolates_environment() {
+        std::env::set_var("PADM_TEST_INTERPOLATE", "secret");
+        assert_eq!(
+            interpolate_env("password = \"${PADM_TEST_INTERPOLATE}\"").unwrap(),
+            "password = \"secret\""
+        );
+        assert_eq!(
+            interpolate_env("$${NOT_A_VAR} costs $5").unwrap(),
+            "${NOT_A_VAR} costs $5"
+        );
+    }
+
+    #[test]
+    fn rejects_unset_and_unterminated_variables() {
+        std::env::remove_var("PADM_TEST_INTERPOLATE_UNSET");
+        let e = interpolate_env("${PADM_TEST_INTERPOLATE_UNSET}").unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        let e = interpolate_env("${PADM_TEST_INTERPOLATE").unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn interpolates_secrets_verbatim() {
+        let secret = "p\"a\\ss\nport = 1";
+        std::env::set_var("PADM_TEST_INTERPOLATE_SECRET", secret);
+        let config = load(
+            "secrets",
+            "ip = \"0.0.0.0\"\n\
+             [[endpoints]]\n\
+             ip = \"192.0.2.1\"\n\
+             username = \"admin\"\n\
+             password = \"${PADM_TEST_INTERPOLATE_SECRET}\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.port(), 8000);
+        assert_eq!(config.endpoints()[0].password(), secret);
+    }
+
+    #[test]
+    fn ignores_variables_in_comments() {
+        std::env::remove_var("PADM_TEST_INTERPOLATE_COMMENTED");
+        let config = load(
+            "comments",
+            "# password = \"${PADM_TEST_INTERPOLATE_COMMENTED}\"\n\
+             ip = \"0.0.0.0\"\n\
+             endpoints = []\n",
+        )
+        .unwrap();
+        assert!(config.endpoints().is_empty());
+    }
+
+    #[test]
+    fn checks_success_predicates() {
+        let non_empty: SuccessPredicate = toml::from_str("pointer = \"/data\"").unwrap();
+        assert!(non_empty.matches(&serde_json::json!({ "data": [{ "id": 1 }] })));
+        // A 200 without any variables counts as a failed probe
+        assert!(!non_empty.matches(&serde_json::json!({ "data": [] })));
+        assert!(!non_empty.matches(&serde_json::json!({})));
+
+        let status: SuccessPredicate =
+            toml::from_str("pointer = \"/status\"\nequals = \"ok\"").unwrap();
+        assert!(status.matches(&serde_json::json!({ "status": "ok" })));
+        assert!(!status.matches(&serde_json::json!({ "status": "error" })));
+        let code: SuccessPredicate = toml::from_str("pointer = \"/code\"\nequals = \"0\"").unwrap();
+        assert!(code.matches(&serde_json::json!({ "code": 0 })));
+    }
+
+    #[test]
+    fn transforms_label_values() {
+        let transform = |settings: &str| -> LabelTransform { toml::from_str(settings).unwrap() };
+
+        // Stripping a common prefix from device names
+        let prefix = transform("on = \"device\"\npattern = \"^SRCOOL-\"\nreplacement = \"\"");
+        assert_eq!(prefix.apply("device", "SRCOOL-rack1"), "rack1");
+        assert_eq!(prefix.apply("device", "PDU-rack1"), "PDU-rack1");
+        assert_eq!(prefix.apply("state", "SRCOOL-rack1"), "SRCOOL-rack1");
+
+        // Rewriting with capture groups
+        let groups =
+            transform("on = \"state\"\npattern = \"^(\\\\w+) Mode$\"\nreplacement = \"${1}\"");
+        assert_eq!(groups.apply("state", "Cooling Mode"), "Cooling");
+
+        // Replacing every match
+        let spaces = transform("on = \"state\"\npattern = \" +\"\nreplacement = \"_\"");
+        assert_eq!(spaces.apply("state", "fan  only mode"), "fan_only_mode");
+
+        assert!(toml::from_str::<LabelTransform>(
+            "on = \"device\"\npattern = \"(\"\nreplacement = \"\""
+        )
+        .is_err());
+    }
+
+    fn config(settings: &str) -> Config {
+        toml::from_str(&format!("ip = \"0.0.0.0\"\n{}", settings)).unwrap()
+    }
+
+    #[test]
+    fn requires_opting_in_to_http() {
+        let http = config(
+            "[[endpoints]]\nip = \"192.0.2.1\"\nscheme = \"http\"\n\
+             username = \"admin\"\npassword = \"secret\"",
+        );
+        let problems = http.validate().unwrap_err();
+        assert!(problems.contains("uses http without allow_insecure_http = true"));
+
+        let allowed = config(
+            "[[endpoints]]\nip = \"192.0.2.1\"\nscheme = \"http\"\n\
+             allow_insecure_http = true\nusername = \"admin\"\npassword = \"secret\"",
+        );
+        assert_eq!(allowed.validate(), Ok(()));
+    }
+
+    #[test]
+    fn requires_endpoints() {
+        let problems = config("endpoints = []").validate().unwrap_err();
+        assert_eq!(problems, "  - No endpoints are configured");
+    }
+
+    #[test]
+    fn reports_duplicate_hosts_once() {
+        let endpoint = "[[endpoints]]\nip = \"192.0.2.1\"\nusername = \"a\"\npassword = \"b\"\n";
+        let problems = config(&endpoint.repeat(3)).validate().unwrap_err();
+        assert_eq!(
+            problems,
+            "  - Endpoint 192.0.2.1:443 is configured more than once"
+        );
+        // Hosts differing only in port are distinct
+        let other_port = format!("{}port = 8443\n", endpoint);
+        assert_eq!(
+            config(&format!("{}{}", endpoint, other_port)).validate(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn requires_credentials() {
+        let problems = config("[[endpoints]]\nip = \"192.0.2.1\"\nusername = \"\"")
+            .validate()
+            .unwrap_err();
+        assert!(problems.contains("Endpoint 192.0.2.1:443 has an empty username"));
+        assert!(problems.contains("Endpoint 192.0.2.1:443 has no password"));
+
+        let problems = config(
+            "[[endpoints]]\nip = \"192.0.2.1\"\ngrant_type = \"client_credentials\"\n\
+             client_id = \"exporter\"",
+        )
+        .validate()
+        .unwrap_err();
+        assert_eq!(problems, "  - Endpoint 192.0.2.1:443 has no client_secret");
+    }
+
+    #[test]
+    fn requires_valid_addresses() {
+        let problems = config(
+            "[[endpoints]]\nip = \"not an address\"\nport = 0\n\
+             username = \"a\"\npassword = \"b\"",
+        )
+        .validate()
+        .unwrap_err();
+        // Every problem is listed, one per line
+        assert_eq!(problems.lines().count(), 2);
+        assert!(problems.contains("has an invalid ip"));
+        assert!(problems.contains("has an invalid port 0"));
+
+        for ip in [
+            "10.0.0.100",
+            "padm-1.example.com",
+            "fe80::1%eth0",
+            "[2001:db8::1]",
+        ] {
+            let valid = config(&format!(
+                "[[endpoints]]\nip = \"{}\"\nusername = \"a\"\npassword = \"b\"",
+                ip
+            ));
+            assert_eq!(valid.validate(), Ok(()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn names_environment_overrides_by_host_and_port() {
+        assert_eq!(env_prefix("10.0.0.100:443"), "PADM_TARGET_10_0_0_100_443");
+        assert_eq!(
+            env_prefix("padm-1.example.com:8443"),
+            "PADM_TARGET_PADM_1_EXAMPLE_COM_8443"
+        );
+        assert_eq!(
+            env_prefix("[2001:db8::1]:443"),
+            "PADM_TARGET__2001_DB8__1__443"
+        );
+    }
+
+    #[test]
+    fn overrides_credentials_from_environment() {
+        std::env::set_var("PADM_TARGET_192_0_2_7_443_PASSWORD", "from-env");
+        let config = load(
+            "env_override",
+            "ip = \"0.0.0.0\"\n\
+             [[endpoints]]\n\
+             ip = \"192.0.2.7\"\n\
+             username = \"admin\"\n\
+             password = \"from-file\"\n\
+             [[endpoints]]\n\
+             ip = \"192.0.2.7\"\n\
+             port = 8443\n\
+             username = \"admin\"\n\
+             password = \"from-file\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.endpoints()[0].password(), "from-env");
+        assert_eq!(config.endpoints()[0].username(), "admin");
+        // Only the endpoint on the matching port is overridden
+        assert_eq!(config.endpoints()[1].password(), "from-file");
+    }
+
+    #[test]
+    fn trims_pasted_whitespace() {
+        let config = load(
+            "trim",
+            "ip = \"0.0.0.0\"\n\
+             [[endpoints]]\n\
+             ip = \"192.0.2.8\\n\"\n\
+             scheme = \" https\"\n\
+             username = \"admin \"\n\
+             password = \" pass word \"\n",
+        )
+        .unwrap();
+        let endpoint = &config.endpoints()[0];
+        assert_eq!(endpoint.ip(), "192.0.2.8");
+        assert_eq!(endpoint.scheme(), "https");
+        assert_eq!(endpoint.username(), "admin");
+        assert_eq!(endpoint.password(), " pass word ");
+        assert_eq!(
+            config.load_warnings(),
+            [
+                "Trimmed whitespace from endpoint ip '192.0.2.8'",
+                "Trimmed whitespace from username of endpoint 192.0.2.8",
+                "Trimmed whitespace from scheme of endpoint 192.0.2.8",
+            ]
+        );
+    }
+
+    #[test]
+    fn warns_about_verifying_zoned_addresses() {
+        let warnings = |settings: &str| {
+            load(
+                "zone",
+                &format!(
+                    "ip = \"0.0.0.0\"\n\
+                     [[endpoints]]\n\
+                     ip = \"fe80::1%2\"\n\
+                     username = \"admin\"\n\
+                     password = \"secret\"\n{}",
+                    settings
+                ),
+            )
+            .unwrap()
+            .load_warnings()
+            .to_vec()
+        };
+        assert_eq!(
+            warnings(""),
+            ["Endpoint fe80::1%2 has a link-local address with a zone, its certificate can't be verified without tls_insecure"]
+        );
+        assert!(warnings("tls_insecure = true").is_empty());
+        assert!(warnings("scheme = \"http\"\nallow_insecure_http = true").is_empty());
+    }
+}
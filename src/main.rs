@@ -7,21 +7,108 @@ use clap::Parser;
 #[derive(Parser, Debug)]
 #[command(version, about)]
 struct Args {
-    /// Path to the config file
-    #[arg(short, long)]
-    config: String,
+    /// Path to the config file, with --test-mapping for its extra_metrics and
+    /// the response layout of its first endpoint
+    #[arg(short, long, required_unless_present = "test_mapping")]
+    config: Option<String>,
+
+    /// Check a saved /api/variables payload against the variable map and exit
+    #[arg(long, value_name = "FILE")]
+    test_mapping: Option<String>,
+
+    /// With --test-mapping, also fail on unmapped labels
+    #[arg(long, requires = "test_mapping")]
+    strict: bool,
+
+    /// With --test-mapping, print the result as JSON
+    #[arg(long, requires = "test_mapping")]
+    json: bool,
+}
+
+fn load_config_or_exit(config_path: &str) -> config::Config {
+    match config::load_config_from_file(config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed loading config {}: {}", config_path, e);
+            std::process::exit(1);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
     let args = Args::parse();
 
-    let config = config::load_config_from_file(&args.config).unwrap();
+    if let Some(path) = args.test_mapping {
+        // Map labels and find variables like the configured endpoint would, if given
+        let config = args.config.as_deref().map(load_config_or_exit);
+        if let Some(config) = &config {
+            for warning in config.register_extra_metrics() {
+                eprintln!("{}", warning);
+            }
+        }
+        let (data_pointer, attributes_key) =
+            match config.as_ref().and_then(|c| c.endpoints().first()) {
+                Some(endpoint) => (endpoint.data_pointer(), endpoint.attributes_key()),
+                None => (config::DEFAULT_DATA_POINTER, config::DEFAULT_ATTRIBUTES_KEY),
+            };
+        let report = std::fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| serde_json::from_str(&text).map_err(|e| e.to_string()))
+            .and_then(|json| {
+                padm_client::mapping::test_mapping(&json, data_pointer, attributes_key)
+                    .map_err(|e| e.to_string())
+            });
+        let report = match report {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("Failed checking mapping of {}: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+        if args.json {
+            println!("{}", report.to_json());
+        } else {
+            print!("{}", report.summary());
+        }
+        let failed = if args.strict {
+            report.fails_strict()
+        } else {
+            report.has_parse_failures()
+        };
+        std::process::exit(if failed { 1 } else { 0 });
+    }
+
+    let config_path = args.config.unwrap();
+    let config = load_config_or_exit(&config_path);
+    if let Err(problems) = config.validate() {
+        eprintln!("Invalid config {}:\n{}", config_path, problems);
+        std::process::exit(1);
+    }
 
     let env = env_logger::Env::default()
         .filter_or("MY_LOG_LEVEL", config.log_level())
         .write_style_or("MY_LOG_LEVEL", config.log_level());
     env_logger::init_from_env(env);
 
-    server::run(config).await
+    // Spans are only emitted when built with tracing support and logging at trace level
+    #[cfg(feature = "trace")]
+    if config.log_level() == "trace" {
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::TRACE)
+            .with_span_events(tracing_subscriber::fmt::format::FmtSpan::CLOSE)
+            .finish();
+        if let Err(e) = tracing::subscriber::set_global_default(subscriber) {
+            log::warn!("Failed setting up tracing: {}", e);
+        }
+    }
+
+    for warning in config.load_warnings() {
+        log::warn!("{}", warning);
+    }
+    for warning in config.register_extra_metrics() {
+        log::warn!("{}", warning);
+    }
+
+    server::run(config, config_path).await
 }
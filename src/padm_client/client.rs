@@ -1,65 +1,157 @@
-use log::error;
+use anyhow::Context;
+use log::{debug, error, warn};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use serde::Deserialize;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::ffi::CString;
+use std::fs;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::{Endpoint, GrantType};
 
 #[derive(Deserialize)]
 pub struct AuthData {
     pub access_token: String,
     pub refresh_token: String,
     pub msg: String,
+    /// Lifetime of the access token in seconds
+    #[serde(default)]
+    pub expires_in: Option<u64>,
+    #[serde(skip, default = "Instant::now")]
+    obtained_at: Instant,
 }
 impl AuthData {
+    /// How long before the real expiry a token is already considered expired
+    const EXPIRY_SLACK: Duration = Duration::from_secs(30);
+
     pub fn new() -> AuthData {
         AuthData {
             access_token: String::new(),
             refresh_token: String::new(),
             msg: String::new(),
+            expires_in: None,
+            obtained_at: Instant::now(),
         }
     }
     pub fn is_empty(&self) -> bool {
         self.access_token.is_empty() || self.refresh_token.is_empty() || self.msg.is_empty()
     }
+    /// Whether the access token is about to expire, if the device told us when
+    pub fn is_expired(&self) -> bool {
+        match self.expires_in {
+            Some(secs) => {
+                self.obtained_at.elapsed() + Self::EXPIRY_SLACK >= Duration::from_secs(secs)
+            }
+            None => false,
+        }
+    }
+}
+
+/// Retries left to a probe cycle, shared by every layer that may retry
+pub struct RetryBudget {
+    limit: Option<u32>,
+    remaining: AtomicU32,
+    spent: AtomicBool,
+    exhausted: AtomicU64,
+}
+impl RetryBudget {
+    fn new(limit: Option<u32>) -> RetryBudget {
+        RetryBudget {
+            limit,
+            remaining: AtomicU32::new(limit.unwrap_or(0)),
+            spent: AtomicBool::new(false),
+            exhausted: AtomicU64::new(0),
+        }
+    }
+    /// Start over with the full budget
+    fn reset(&self) {
+        self.remaining
+            .store(self.limit.unwrap_or(0), Ordering::Relaxed);
+        self.spent.store(false, Ordering::Relaxed);
+    }
+    /// Consume one retry, returning false if none are left
+    fn take(&self) -> bool {
+        if self.limit.is_none() {
+            return true;
+        }
+        let taken = self
+            .remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok();
+        if !taken && !self.spent.swap(true, Ordering::Relaxed) {
+            self.exhausted.fetch_add(1, Ordering::Relaxed);
+        }
+        taken
+    }
 }
 
 /*
 * Client for interacting with PADM devices
 */
 pub struct PADMClient {
-    client: reqwest::Client,
+    endpoint: Endpoint,
+    client: RefCell<reqwest::Client>,
     host: String,
+    authority: String,
     scheme: String,
     interval: u64,
-    username: String,
-    password: String,
+    dns_retries: u32,
+    dns_retry_delay: Duration,
+    username: RefCell<String>,
+    password: RefCell<String>,
+    grant_type: GrantType,
+    client_id: String,
+    client_secret: String,
     auth_data: RefCell<AuthData>,
+    auth_failures: Cell<u32>,
+    auth_errors: Cell<u64>,
+    auth_rate_limit: Option<u32>,
+    auth_rate_window: Duration,
+    auth_attempts: RefCell<VecDeque<Instant>>,
+    auth_throttled: Cell<bool>,
+    last_connect_error: RefCell<Option<String>>,
+    connect_error_count: Cell<u32>,
+    rebuilds: Cell<u64>,
+    cert_expiry: Cell<Option<i64>>,
+    retry_budget: Arc<RetryBudget>,
 }
 impl PADMClient {
-    pub fn new(
-        host: &str,
-        scheme: &str,
-        tls_insecure: bool,
-        interval: u64,
-        username: &str,
-        password: &str,
-    ) -> PADMClient {
-        let mut client_builder = reqwest::Client::builder();
-        // Disable SSL verification if asked
-        if tls_insecure {
-            client_builder = client_builder.danger_accept_invalid_certs(true);
-        }
-
-        // Get a new reqwest client
-        let client = client_builder.build().unwrap();
-
-        PADMClient {
-            client,
-            host: host.to_string(),
-            scheme: scheme.to_string(),
-            username: username.to_string(),
-            password: password.to_string(),
-            interval,
+    pub fn new(endpoint: &Endpoint) -> Result<PADMClient, anyhow::Error> {
+        let retry_budget = Arc::new(RetryBudget::new(endpoint.retry_budget()));
+        let (client, authority) = build_client(endpoint, &retry_budget)?;
+
+        Ok(PADMClient {
+            endpoint: endpoint.clone(),
+            client: RefCell::new(client),
+            host: endpoint.host(),
+            authority,
+            scheme: endpoint.scheme().to_string(),
+            username: RefCell::new(endpoint.username().to_string()),
+            password: RefCell::new(endpoint.password().to_string()),
+            grant_type: endpoint.grant_type(),
+            client_id: endpoint.client_id().to_string(),
+            client_secret: endpoint.client_secret().to_string(),
+            interval: endpoint.interval(),
+            dns_retries: endpoint.dns_retries(),
+            dns_retry_delay: Duration::from_millis(endpoint.dns_retry_delay_ms()),
             auth_data: RefCell::new(AuthData::new()),
-        }
+            auth_failures: Cell::new(0),
+            auth_errors: Cell::new(0),
+            auth_rate_limit: endpoint.auth_rate_limit(),
+            auth_rate_window: Duration::from_secs(endpoint.auth_rate_window()),
+            auth_attempts: RefCell::new(VecDeque::new()),
+            auth_throttled: Cell::new(false),
+            last_connect_error: RefCell::new(None),
+            connect_error_count: Cell::new(0),
+            rebuilds: Cell::new(0),
+            cert_expiry: Cell::new(None),
+            retry_budget,
+        })
     }
     pub fn interval(&self) -> u64 {
         self.interval
@@ -67,62 +159,332 @@ impl PADMClient {
     pub fn host(&self) -> &str {
         &self.host
     }
+    /// Number of consecutive authentication attempts rejected due to bad credentials
+    pub fn auth_failures(&self) -> u32 {
+        self.auth_failures.get()
+    }
+    /// Total number of failed authentication attempts, for any reason
+    pub fn auth_errors(&self) -> u64 {
+        self.auth_errors.get()
+    }
+    /// Whether the last authentication attempt had to wait on the rate limit
+    pub fn auth_throttled(&self) -> bool {
+        self.auth_throttled.get()
+    }
+    /// Number of times the underlying HTTP client was rebuilt
+    pub fn rebuilds(&self) -> u64 {
+        self.rebuilds.get()
+    }
+    /// Refill the retry budget at the start of a probe cycle
+    pub fn begin_cycle(&self) {
+        self.retry_budget.reset();
+    }
+    /// Number of times a probe cycle ran out of retries
+    pub fn retry_budget_exhausted(&self) -> u64 {
+        self.retry_budget.exhausted.load(Ordering::Relaxed)
+    }
+    /// Expiry of the certificate the device last presented, in seconds since the epoch
+    pub fn cert_expiry(&self) -> Option<i64> {
+        self.cert_expiry.get()
+    }
+    /// Remember when the certificate presented on a response expires
+    fn track_cert_expiry(&self, response: &reqwest::Response) {
+        let der = match response
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+        {
+            Some(der) => der,
+            None => return,
+        };
+        match x509_parser::parse_x509_certificate(der) {
+            Ok((_, cert)) => self
+                .cert_expiry
+                .set(Some(cert.validity().not_after.timestamp())),
+            Err(e) => debug!(
+                "Failed parsing certificate of endpoint {}: {}",
+                self.host(),
+                e
+            ),
+        }
+    }
+    /// Rebuild the HTTP client if the same connection error keeps repeating,
+    /// in case it is stuck in a bad state
+    fn track_connect_error(&self, result: &Result<reqwest::Response, reqwest::Error>) {
+        let message = match result {
+            Err(e) if e.is_connect() || e.is_request() => e.to_string(),
+            _ => {
+                self.last_connect_error.replace(None);
+                self.connect_error_count.set(0);
+                return;
+            }
+        };
+        if self.last_connect_error.borrow().as_deref() == Some(message.as_str()) {
+            self.connect_error_count
+                .set(self.connect_error_count.get() + 1);
+        } else {
+            self.last_connect_error.replace(Some(message));
+            self.connect_error_count.set(1);
+        }
+
+        if self.connect_error_count.get() < self.endpoint.rebuild_after_errors() {
+            return;
+        }
+        match build_client(&self.endpoint, &self.retry_budget) {
+            Ok((client, _)) => {
+                warn!(
+                    "Rebuilding client for endpoint {} after {} identical connection errors",
+                    self.host(),
+                    self.connect_error_count.get()
+                );
+                self.client.replace(client);
+                self.rebuilds.set(self.rebuilds.get() + 1);
+            }
+            Err(e) => error!("Failed rebuilding client for {}: {:#}", self.host(), e),
+        }
+        self.last_connect_error.replace(None);
+        self.connect_error_count.set(0);
+    }
+    /// Wait until another authentication attempt is allowed by the rate limit
+    async fn throttle_auth(&self) {
+        let limit = match self.auth_rate_limit {
+            Some(l) => l as usize,
+            None => return,
+        };
+        let now = Instant::now();
+        let oldest = {
+            let mut attempts = self.auth_attempts.borrow_mut();
+            while attempts
+                .front()
+                .is_some_and(|t| now.duration_since(*t) >= self.auth_rate_window)
+            {
+                attempts.pop_front();
+            }
+            if attempts.len() < limit {
+                None
+            } else {
+                attempts.front().copied()
+            }
+        };
+        self.auth_throttled.set(oldest.is_some());
+        if let Some(oldest) = oldest {
+            let wait = self.auth_rate_window - now.duration_since(oldest);
+            warn!(
+                "Authentication rate limit reached for endpoint {}, waiting {}s",
+                self.host(),
+                wait.as_secs()
+            );
+            async_std::task::sleep(wait).await;
+            self.auth_attempts.borrow_mut().pop_front();
+        }
+        self.auth_attempts.borrow_mut().push_back(Instant::now());
+    }
+    /// Send a request, retrying shortly after transient DNS resolution failures
+    async fn send(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let mut attempt = 0;
+        loop {
+            let result = match request.try_clone() {
+                Some(r) => r.send().await,
+                None => return request.send().await,
+            };
+            self.track_connect_error(&result);
+            if let Ok(response) = &result {
+                self.track_cert_expiry(response);
+            }
+            match result {
+                Err(e)
+                    if is_dns_error(&e)
+                        && attempt < self.dns_retries
+                        && self.retry_budget.take() =>
+                {
+                    attempt += 1;
+                    warn!(
+                        "DNS resolution failed for endpoint {} (attempt {}/{}): {}",
+                        self.host(),
+                        attempt,
+                        self.dns_retries,
+                        e
+                    );
+                    async_std::task::sleep(self.dns_retry_delay).await;
+                }
+                result => return result,
+            }
+        }
+    }
     /// Log into the device and retrieve authentication data
+    #[cfg_attr(
+        feature = "trace",
+        tracing::instrument(name = "authenticate", skip_all, fields(target = %self.host))
+    )]
     async fn authenticate(&self) -> Result<(), reqwest::Error> {
-        let request_url = format!("https://{}/api/oauth/token?grant_type=password", self.host);
-        let params = [("username", &self.username), ("password", &self.password)];
+        let request_url = format!(
+            "{}://{}/api/oauth/token?grant_type={}",
+            self.scheme,
+            self.authority,
+            self.grant_type.as_str()
+        );
+        let params = match self.grant_type {
+            GrantType::Password => [
+                ("username", self.username.borrow().to_owned()),
+                ("password", self.password.borrow().to_owned()),
+            ],
+            GrantType::ClientCredentials => [
+                ("client_id", self.client_id.to_owned()),
+                ("client_secret", self.client_secret.to_owned()),
+            ],
+        };
+
+        self.throttle_auth().await;
 
-        let response = self.client.post(&request_url).form(&params).send().await;
+        let request = self.client.borrow().post(&request_url).form(&params);
+        let response = self.send(request).await;
 
+        let result = self.handle_auth_response(response).await;
+        if result.is_err() {
+            self.auth_errors.set(self.auth_errors.get() + 1);
+        }
+        result
+    }
+    /// Store the authentication data from a token response
+    async fn handle_auth_response(
+        &self,
+        response: Result<reqwest::Response, reqwest::Error>,
+    ) -> Result<(), reqwest::Error> {
         match response {
             Err(e) => {
                 error!("Authentication failed on endpoint {}: {}", self.host(), e);
                 Err(e)
             }
-            Ok(r) => match r.json().await {
-                Err(e) => {
-                    error!(
-                        "Malformed auth response from endpoint {}: {}",
-                        self.host(),
-                        e
-                    );
-                    Err(e)
+            Ok(r) => {
+                if let Err(e) = r.error_for_status_ref() {
+                    // Rejected credentials won't fix themselves on retry
+                    if r.status().is_client_error()
+                        && r.text().await.unwrap_or_default().contains("invalid_grant")
+                    {
+                        self.auth_failures.set(self.auth_failures.get() + 1);
+                    }
+                    error!("Authentication failed on endpoint {}: {}", self.host(), e);
+                    return Err(e);
                 }
-                Ok(j) => {
-                    self.auth_data.replace(j);
-                    Ok(())
+                match r.json().await {
+                    Err(e) => {
+                        error!(
+                            "Malformed auth response from endpoint {}: {}",
+                            self.host(),
+                            e
+                        );
+                        Err(e)
+                    }
+                    Ok(j) => {
+                        self.auth_data.replace(j);
+                        self.auth_failures.set(0);
+                        Ok(())
+                    }
                 }
-            },
+            }
+        }
+    }
+    /// Renew the access token using the refresh token
+    async fn refresh(&self) -> Result<(), reqwest::Error> {
+        let request_url = format!(
+            "{}://{}/api/oauth/token?grant_type=refresh_token",
+            self.scheme, self.authority
+        );
+        let refresh_token = self.auth_data.borrow().refresh_token.to_owned();
+        let params = [("refresh_token", &refresh_token)];
+
+        let request = self.client.borrow().post(&request_url).form(&params);
+        let response = self.send(request).await.and_then(|r| r.error_for_status());
+        let result = match response {
+            Ok(r) => r.json::<AuthData>().await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(auth_data) => {
+                self.auth_data.replace(auth_data);
+                Ok(())
+            }
+            Err(e) => {
+                debug!(
+                    "Refreshing token failed on endpoint {}, logging in again: {}",
+                    self.host(),
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+    /// Replace the credentials in use and authenticate with them right away
+    pub async fn rotate_credentials(
+        &self,
+        username: String,
+        password: String,
+    ) -> Result<(), reqwest::Error> {
+        if self.grant_type == GrantType::ClientCredentials {
+            warn!(
+                "Endpoint {} uses the client_credentials grant, rotated credentials are unused",
+                self.host()
+            );
         }
+        self.username.replace(username);
+        self.password.replace(password);
+        self.auth_data.replace(AuthData::new());
+        self.authenticate().await
     }
-    async fn raw_get(&self, url: &str) -> Result<reqwest::Response, reqwest::Error> {
-        self.client
-            .get(url)
-            .header(
-                reqwest::header::AUTHORIZATION,
-                format!("Bearer {}", &self.auth_data.borrow().access_token),
-            )
-            .send()
-            .await
+    async fn raw_request(
+        &self,
+        method: &reqwest::Method,
+        url: &str,
+        body: Option<&str>,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let mut request = self.client.borrow().request(method.clone(), url).header(
+            reqwest::header::AUTHORIZATION,
+            format!("Bearer {}", &self.auth_data.borrow().access_token),
+        );
+        if let Some(body) = body {
+            request = request
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.to_string());
+        }
+        self.send(request).await
     }
-    /// Do an authenticated GET request
-    pub async fn do_get(&self, path: &str) -> Result<reqwest::Response, reqwest::Error> {
-        let url = format!("{}://{}{}", self.scheme, self.host, path);
+    /// Do an authenticated request with an optional JSON body
+    pub async fn do_request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<&str>,
+    ) -> Result<reqwest::Response, reqwest::Error> {
+        let url = format!("{}://{}{}", self.scheme, self.authority, path);
 
         // Authenticate if never authenticated before
         if self.auth_data.borrow().is_empty() {
             self.authenticate().await?;
+        } else if self.auth_data.borrow().is_expired() {
+            // Renew the token ahead of time rather than waiting to be rejected
+            debug!(
+                "Access token for {} is about to expire, renewing",
+                self.host()
+            );
+            if self.refresh().await.is_err() {
+                self.authenticate().await?;
+            }
         }
 
-        let response = self.raw_get(&url).await;
+        let response = self.raw_request(&method, &url, body).await;
         match response {
             Ok(r) => match r.error_for_status() {
                 Ok(r) => Ok(r),
                 Err(err) => match err.status() {
-                    Some(reqwest::StatusCode::UNAUTHORIZED) => {
-                        // Authenticate again if needed
-                        self.authenticate().await?;
-                        Ok(self.raw_get(&url).await?)
+                    Some(reqwest::StatusCode::UNAUTHORIZED) if self.retry_budget.take() => {
+                        // Renew the token if possible, otherwise log in again
+                        if self.refresh().await.is_err() {
+                            self.authenticate().await?;
+                        }
+                        Ok(self.raw_request(&method, &url, body).await?)
                     }
                     // Otherwise just return the error
                     _ => Err(err),
@@ -132,3 +494,333 @@ impl PADMClient {
         }
     }
 }
+
+/// Redirects followed per request, matching the reqwest default
+const MAX_REDIRECTS: usize = 10;
+
+/// Build the underlying HTTP client for an endpoint, along with the authority to put in URLs
+fn build_client(
+    endpoint: &Endpoint,
+    retry_budget: &Arc<RetryBudget>,
+) -> Result<(reqwest::Client, String), anyhow::Error> {
+    let mut client_builder =
+        reqwest::Client::builder().timeout(Duration::from_secs(endpoint.timeout()));
+    // Count redirects against the retry budget of the probe cycle
+    if endpoint.retry_budget().is_some() {
+        let retry_budget = retry_budget.clone();
+        client_builder =
+            client_builder.redirect(reqwest::redirect::Policy::custom(move |attempt| {
+                if attempt.previous().len() >= MAX_REDIRECTS {
+                    attempt.error("too many redirects")
+                } else if retry_budget.take() {
+                    attempt.follow()
+                } else {
+                    attempt.error("retry budget exhausted")
+                }
+            }));
+    }
+    // Disable SSL verification if asked
+    if endpoint.tls_insecure() {
+        client_builder = client_builder.danger_accept_invalid_certs(true);
+    }
+    // Trust a private CA while keeping verification on
+    if let Some(path) = endpoint.ca_cert() {
+        if endpoint.tls_insecure() {
+            warn!(
+                "Endpoint {} sets both ca_cert and tls_insecure, certificates won't be verified",
+                endpoint.host()
+            );
+        }
+        let pem =
+            fs::read(path).with_context(|| format!("Failed reading CA certificate '{}'", path))?;
+        let cert = reqwest::Certificate::from_pem(&pem)
+            .with_context(|| format!("Failed loading CA certificate '{}'", path))?;
+        client_builder = client_builder.add_root_certificate(cert);
+    }
+    // Keep the peer certificate around to report its expiry
+    if endpoint.scheme() == "https" {
+        client_builder = client_builder.tls_info(true);
+    }
+    // Refuse to negotiate below the configured TLS version
+    if let Some(version) = endpoint.min_tls_version() {
+        client_builder = client_builder.min_tls_version(version);
+    }
+    // Present a client identity if one is configured
+    if let Some(path) = endpoint.client_identity_p12() {
+        let der =
+            fs::read(path).with_context(|| format!("Failed reading client identity '{}'", path))?;
+        let identity =
+            reqwest::Identity::from_pkcs12_der(&der, endpoint.client_identity_password())
+                .with_context(|| format!("Failed loading client identity '{}'", path))?;
+        client_builder = client_builder.identity(identity);
+    } else if endpoint.has_client_identity_password() {
+        anyhow::bail!(
+            "Endpoint {} sets client_identity_password without client_identity_p12",
+            endpoint.host()
+        );
+    }
+    // Or build one from a PEM certificate and key
+    match (endpoint.client_cert(), endpoint.client_key()) {
+        (Some(_), Some(_)) if endpoint.client_identity_p12().is_some() => anyhow::bail!(
+            "Endpoint {} sets both client_identity_p12 and client_cert, only one is allowed",
+            endpoint.host()
+        ),
+        (Some(cert_path), Some(key_path)) => {
+            let cert = fs::read(cert_path)
+                .with_context(|| format!("Failed reading client certificate '{}'", cert_path))?;
+            let key = fs::read(key_path)
+                .with_context(|| format!("Failed reading client key '{}'", key_path))?;
+            let identity = reqwest::Identity::from_pkcs8_pem(&cert, &key)
+                .with_context(|| format!("Failed loading client certificate '{}'", cert_path))?;
+            client_builder = client_builder.identity(identity);
+        }
+        (None, None) => (),
+        _ => anyhow::bail!(
+            "Endpoint {} must set client_cert and client_key together",
+            endpoint.host()
+        ),
+    }
+
+    // Send extra headers with every request; headers set per request, such
+    // as the bearer token, take precedence
+    if let Some(headers) = endpoint.headers() {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_bytes())
+                .with_context(|| format!("Invalid header name '{}'", name))?;
+            let value = HeaderValue::from_str(value)
+                .with_context(|| format!("Invalid value for header '{}'", name))?;
+            header_map.insert(name, value);
+        }
+        client_builder = client_builder.default_headers(header_map);
+    }
+
+    // Go through a proxy, except for hosts listed in NO_PROXY
+    if let Some(url) = endpoint.proxy() {
+        let proxy = reqwest::Proxy::all(url)
+            .with_context(|| format!("Invalid proxy URL '{}'", url))?
+            .no_proxy(reqwest::NoProxy::from_env());
+        client_builder = client_builder.proxy(proxy);
+    }
+
+    // Pin host names to fixed addresses, e.g. for split-horizon DNS
+    for (host, ip) in endpoint.resolve_overrides().map_err(anyhow::Error::msg)? {
+        client_builder = client_builder.resolve(&host, SocketAddr::new(ip, endpoint.port()));
+    }
+
+    // Zone identifiers can't be expressed in a URL, so connect to link-local
+    // addresses through a placeholder name resolved to the scoped address
+    let mut authority = endpoint.host();
+    if let Some((connect_host, connect_port)) = endpoint.connect_address() {
+        let addr = (connect_host, connect_port)
+            .to_socket_addrs()
+            .with_context(|| format!("Failed resolving connect address '{}'", connect_host))?
+            .next()
+            .with_context(|| format!("No address found for '{}'", connect_host))?;
+        // The connection port always comes from the URL. Host names keep
+        // being used for SNI and certificate checks, while addresses can't
+        // be overridden through the resolver and are replaced outright
+        let ip = endpoint.ip();
+        let unbracketed = ip.trim_start_matches('[').trim_end_matches(']');
+        if unbracketed.parse::<IpAddr>().is_ok() {
+            authority = addr.to_string();
+        } else {
+            client_builder = client_builder.resolve(ip, addr);
+            authority = format!("{}:{}", ip, connect_port);
+        }
+    } else if let Some((name, addr)) = link_local_target(endpoint.ip(), endpoint.port())? {
+        client_builder = client_builder.resolve(&name, addr);
+        authority = format!("{}:{}", name, endpoint.port());
+    }
+
+    // Get a new reqwest client
+    Ok((client_builder.build()?, authority))
+}
+
+/// Whether a request failed because the host name could not be resolved
+fn is_dns_error(err: &reqwest::Error) -> bool {
+    if !err.is_connect() {
+        return false;
+    }
+    let mut source = err.source();
+    while let Some(e) = source {
+        if e.to_string().starts_with("dns error") {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Map an IPv6 link-local address with a zone (e.g. `fe80::1%eth0`) to a
+/// placeholder host name and the scoped socket address it should resolve to
+fn link_local_target(ip: &str, port: u16) -> Result<Option<(String, SocketAddr)>, anyhow::Error> {
+    let (addr, zone) = match ip
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .split_once('%')
+    {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+    let addr: Ipv6Addr = addr
+        .parse()
+        .with_context(|| format!("Invalid IPv6 address '{}'", addr))?;
+
+    // Zones may be given as an interface name or a numeric index
+    let scope_id = match zone.parse::<u32>() {
+        Ok(index) => index,
+        Err(_) => {
+            let name = CString::new(zone)?;
+            // SAFETY: `name` is a valid NUL-terminated string for the duration of the call
+            match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+                0 => anyhow::bail!("Unknown network interface '{}' in address '{}'", zone, ip),
+                index => index,
+            }
+        }
+    };
+
+    let name = format!(
+        "{}-{}.link-local",
+        addr.segments()
+            .iter()
+            .map(|s| format!("{:x}", s))
+            .collect::<Vec<_>>()
+            .join("-"),
+        scope_id
+    );
+    Ok(Some((
+        name,
+        SocketAddr::V6(SocketAddrV6::new(addr, port, 0, scope_id)),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn endpoint(settings: &str) -> Endpoint {
+        toml::from_str(&format!(
+            "ip = \"127.0.0.1\"\nscheme = \"http\"\nusername = \"admin\"\npassword = \"secret\"\n{}",
+            settings
+        ))
+        .unwrap()
+    }
+
+    /// Answer token requests and variable listings like a device would
+    async fn serve_device(listener: TcpListener) {
+        loop {
+            let (mut stream, _) = match listener.accept().await {
+                Ok(s) => s,
+                Err(_) => return,
+            };
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                match stream.read(&mut buf).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+            let request = String::from_utf8_lossy(&request);
+            let body = match request.contains("/api/oauth/token") {
+                true => r#"{"access_token":"a","refresh_token":"r","msg":"ok"}"#,
+                false => r#"{"data":[]}"#,
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn rebuilds_client_after_repeated_connection_errors() {
+        // Find a port nothing listens on yet
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = PADMClient::new(&endpoint(&format!(
+            "port = {}\nrebuild_after_errors = 2",
+            port
+        )))
+        .unwrap();
+        let probe = || client.do_request(reqwest::Method::GET, "/api/variables", None);
+
+        assert!(probe().await.is_err());
+        assert_eq!(client.rebuilds(), 0);
+        assert!(probe().await.is_err());
+        assert_eq!(client.rebuilds(), 1);
+
+        // Probing works again with the rebuilt client once the device is back
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        tokio::spawn(serve_device(listener));
+        let response = probe().await.unwrap();
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        assert_eq!(client.rebuilds(), 1);
+    }
+
+    #[test]
+    fn exhausts_and_refills_retry_budget() {
+        let budget = RetryBudget::new(Some(2));
+        assert!(budget.take());
+        assert!(budget.take());
+        assert!(!budget.take());
+        assert!(!budget.take());
+        // Exhaustion counts once per cycle
+        assert_eq!(budget.exhausted.load(Ordering::Relaxed), 1);
+
+        budget.reset();
+        assert!(budget.take());
+        assert!(budget.take());
+        assert!(!budget.take());
+        assert_eq!(budget.exhausted.load(Ordering::Relaxed), 2);
+
+        let unlimited = RetryBudget::new(None);
+        assert!((0..100).all(|_| unlimited.take()));
+        assert_eq!(unlimited.exhausted.load(Ordering::Relaxed), 0);
+    }
+
+    fn auth_data(expires_in: Option<u64>, age: u64) -> AuthData {
+        AuthData {
+            access_token: String::from("a"),
+            refresh_token: String::from("r"),
+            msg: String::from("ok"),
+            expires_in,
+            obtained_at: Instant::now() - Duration::from_secs(age),
+        }
+    }
+
+    #[test]
+    fn expires_tokens_ahead_of_time() {
+        // Tokens count as expired from 30s before their actual expiry
+        assert!(!auth_data(Some(60), 0).is_expired());
+        assert!(!auth_data(Some(60), 29).is_expired());
+        assert!(auth_data(Some(60), 30).is_expired());
+        assert!(auth_data(Some(60), 90).is_expired());
+        assert!(auth_data(Some(10), 0).is_expired());
+        // Without a lifetime the device decides, by rejecting the token
+        assert!(!auth_data(None, 86400).is_expired());
+    }
+
+    #[test]
+    fn maps_zoned_addresses_to_placeholders() {
+        let (name, addr) = link_local_target("fe80::1%2", 443).unwrap().unwrap();
+        assert_eq!(name, "fe80-0-0-0-0-0-0-1-2.link-local");
+        assert_eq!(addr.to_string(), "[fe80::1%2]:443");
+        // Brackets are optional
+        let (bracketed, _) = link_local_target("[fe80::1%2]", 443).unwrap().unwrap();
+        assert_eq!(bracketed, name);
+
+        assert!(link_local_target("fe80::1", 443).unwrap().is_none());
+        assert!(link_local_target("192.0.2.1", 443).unwrap().is_none());
+        assert!(link_local_target("fe80::zz%2", 443).is_err());
+        assert!(link_local_target("fe80::1%no-such-interface", 443).is_err());
+    }
+}
@@ -1,47 +1,229 @@
+use log::warn;
 use serde_json;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use crate::padm_client::variables::{Variable, is_metric, unpack_variable};
 
+/// Number of times a device ID was seen with differing device names
+pub static DEVICE_ID_CONFLICTS: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug, Clone)]
 pub struct Device {
     pub id: i64,
     pub name: String,
     pub device_type: String,
     pub variables: Vec<Variable>,
+    /// Extra labels applied to every variable of this device
+    pub labels: HashMap<String, String>,
+}
+
+/// Variable attributes of a response item, found under a key or a JSON pointer
+fn attributes<'a>(
+    item: &'a serde_json::Value,
+    attributes_key: &str,
+) -> Option<&'a serde_json::Map<String, serde_json::Value>> {
+    match attributes_key.is_empty() || attributes_key.starts_with('/') {
+        true => item.pointer(attributes_key),
+        false => item.get(attributes_key),
+    }
+    .and_then(|a| a.as_object())
 }
 
-pub fn load_all_from(json: &serde_json::Value) -> Result<Vec<Device>, std::io::Error> {
+pub fn load_all_from(
+    items: &[serde_json::Value],
+    attributes_key: &str,
+) -> Result<Vec<Device>, std::io::Error> {
+    load_all_reporting(items, attributes_key, &mut Vec::new())
+}
+
+/// Like `load_all_from`, also collecting the label of every mapped variable
+/// that had to be skipped along with the reason
+pub fn load_all_reporting(
+    items: &[serde_json::Value],
+    attributes_key: &str,
+    skipped: &mut Vec<(String, String)>,
+) -> Result<Vec<Device>, std::io::Error> {
     let mut devices: Vec<Device> = Vec::new();
 
-    for item in json["data"].as_array().unwrap() {
-        'inner: for item in &item["attributes"].as_object() {
-            if !is_metric(item) {
+    for item in items {
+        let item = match attributes(item, attributes_key) {
+            Some(i) => i,
+            None => continue,
+        };
+        if !is_metric(item) {
+            continue;
+        }
+        let label = item
+            .get("label")
+            .and_then(|l| l.as_str())
+            .unwrap_or_default();
+        let (id, name) = match (
+            item.get("device_id").and_then(|i| i.as_i64()),
+            item.get("device_name").and_then(|n| n.as_str()),
+        ) {
+            (Some(id), Some(name)) => (id, name),
+            _ => {
+                warn!(
+                    "Skipping variable '{}' without a numeric device ID and a device name",
+                    label
+                );
+                skipped.push((
+                    label.to_string(),
+                    String::from("missing numeric device ID or device name"),
+                ));
                 continue;
             }
-            for device in &mut devices {
-                if device.id == item["device_id"].as_i64().unwrap() {
-                    let variable = unpack_variable(item);
-                    device.variables.push(variable);
-                    break 'inner;
-                }
+        };
+        if name.is_empty() {
+            warn!(
+                "Skipping variable '{}' of device ID {} with an empty device name",
+                label, id
+            );
+            skipped.push((label.to_string(), String::from("empty device name")));
+            continue;
+        }
+        let variable = match unpack_variable(item) {
+            Ok(v) => v,
+            Err(e) => {
+                warn!("Skipping variable of device ID {}: {}", id, e);
+                skipped.push((label.to_string(), e));
+                continue;
+            }
+        };
+        if let Some(device) = devices.iter_mut().find(|d| d.id == id) {
+            if device.name != name {
+                warn!(
+                    "Device ID {} reported with conflicting names '{}' and '{}'",
+                    id, device.name, name
+                );
+                DEVICE_ID_CONFLICTS.fetch_add(1, Ordering::Relaxed);
             }
+            device.variables.push(variable);
+            continue;
+        }
 
-            let mut variables = Vec::new();
-            let variable = unpack_variable(item);
-            variables.push(variable);
+        let variables = vec![variable];
 
-            let id = item["device_id"].as_i64().unwrap();
-            let name = item["device_name"].as_str().unwrap().to_string();
-            let device_type = item["device_type"].as_str().unwrap().to_string();
+        let name = name.to_string();
+        let device_type = item
+            .get("device_type")
+            .and_then(|t| t.as_str())
+            .unwrap_or_default()
+            .to_string();
 
-            devices.push(Device {
-                id,
-                name,
-                device_type,
-                variables,
-            });
-        }
+        devices.push(Device {
+            id,
+            name,
+            device_type,
+            variables,
+            labels: HashMap::new(),
+        });
     }
 
     Ok(devices)
 }
+
+/// Variable attributes in a response that don't map to any metric
+fn unmapped<'a>(
+    items: &'a [serde_json::Value],
+    attributes_key: &'a str,
+) -> impl Iterator<Item = &'a serde_json::Map<String, serde_json::Value>> {
+    items
+        .iter()
+        .filter_map(move |item| attributes(item, attributes_key))
+        .filter(|item| !is_metric(item))
+}
+
+/// Count the variables in a response that don't map to any metric
+pub fn count_unmapped(items: &[serde_json::Value], attributes_key: &str) -> usize {
+    unmapped(items, attributes_key).count()
+}
+
+/// Labels of the variables in a response that don't map to any metric
+pub fn unmapped_labels(items: &[serde_json::Value], attributes_key: &str) -> BTreeSet<String> {
+    unmapped(items, attributes_key)
+        .filter_map(|item| item.get("label").and_then(|l| l.as_str()))
+        .map(|label| label.to_string())
+        .collect()
+}
+
+/// All values each enum variable in a response can take, by variable label
+pub fn enum_values(
+    items: &[serde_json::Value],
+    attributes_key: &str,
+) -> BTreeMap<String, BTreeSet<String>> {
+    let mut enums: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for item in items.iter().filter_map(|i| attributes(i, attributes_key)) {
+        let label = item.get("label").and_then(|l| l.as_str());
+        let values = item.get("enum_values").and_then(|v| v.as_array());
+        let (label, values) = match (label, values) {
+            (Some(label), Some(values)) => (label, values),
+            _ => continue,
+        };
+        // Values are either plain names or objects carrying a name
+        let names = values.iter().filter_map(|v| match v {
+            serde_json::Value::String(s) => Some(s.as_str()),
+            v => v["name"].as_str(),
+        });
+        enums
+            .entry(label.to_string())
+            .or_default()
+            .extend(names.map(|n| n.to_string()));
+    }
+    enums
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(device_id: serde_json::Value, device_name: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({ "attributes": {
+            "label": "Humidity (%)",
+            "value": "45%",
+            "device_id": device_id,
+            "device_name": device_name,
+        }})
+    }
+
+    #[test]
+    fn counts_device_id_conflicts() {
+        let items = [
+            item(serde_json::json!(1), serde_json::json!("cool-1")),
+            item(serde_json::json!(1), serde_json::json!("cool-one")),
+            item(serde_json::json!(2), serde_json::json!("cool-2")),
+        ];
+        let before = DEVICE_ID_CONFLICTS.load(Ordering::Relaxed);
+        let devices = load_all_from(&items, "attributes").unwrap();
+        assert_eq!(DEVICE_ID_CONFLICTS.load(Ordering::Relaxed) - before, 1);
+        // The first name wins
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].name, "cool-1");
+        assert_eq!(devices[0].variables.len(), 2);
+    }
+
+    #[test]
+    fn skips_devices_with_empty_or_malformed_names() {
+        let items = [
+            item(serde_json::json!(3), serde_json::json!("")),
+            item(serde_json::json!(4), serde_json::json!(null)),
+            item(serde_json::json!(5), serde_json::json!(42)),
+            item(serde_json::json!("6"), serde_json::json!("cool-6")),
+            item(serde_json::json!(7), serde_json::json!("cool-7")),
+        ];
+        let mut skipped = Vec::new();
+        let devices = load_all_reporting(&items, "attributes", &mut skipped).unwrap();
+        assert_eq!(skipped.len(), 4);
+        assert_eq!(
+            skipped[0],
+            (
+                String::from("Humidity (%)"),
+                String::from("empty device name")
+            )
+        );
+        // Valid devices after malformed ones still get their own series
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "cool-7");
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeSet;
+
+use crate::padm_client::device::{load_all_reporting, unmapped_labels};
+
+/// Result of checking a saved `/api/variables` payload against the variable map
+#[derive(Debug, Default)]
+pub struct MappingReport {
+    pub mapped: BTreeSet<String>,
+    pub unmapped: BTreeSet<String>,
+    pub parse_failures: BTreeSet<(String, String)>,
+}
+impl MappingReport {
+    /// Whether any mapped variable had a value that isn't numeric
+    pub fn has_parse_failures(&self) -> bool {
+        !self.parse_failures.is_empty()
+    }
+    /// Whether the payload fails strict validation (unmapped labels or parse failures)
+    pub fn fails_strict(&self) -> bool {
+        !self.unmapped.is_empty() || self.has_parse_failures()
+    }
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "mapped": self.mapped,
+            "unmapped": self.unmapped,
+            "parse_failures": self
+                .parse_failures
+                .iter()
+                .map(|(label, value)| serde_json::json!({ "label": label, "value": value }))
+                .collect::<Vec<_>>(),
+        })
+    }
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        out.push_str(format!("Mapped labels ({}):\n", self.mapped.len()).as_str());
+        for label in &self.mapped {
+            out.push_str(format!("  {}\n", label).as_str());
+        }
+        out.push_str(format!("Unmapped labels ({}):\n", self.unmapped.len()).as_str());
+        for label in &self.unmapped {
+            out.push_str(format!("  {}\n", label).as_str());
+        }
+        out.push_str(format!("Parse failures ({}):\n", self.parse_failures.len()).as_str());
+        for (label, value) in &self.parse_failures {
+            out.push_str(format!("  {}: '{}'\n", label, value).as_str());
+        }
+        out
+    }
+}
+
+/// Check which labels in a payload map to metrics, without any network access,
+/// finding variables the same way probes do
+pub fn test_mapping(
+    json: &serde_json::Value,
+    data_pointer: &str,
+    attributes_key: &str,
+) -> Result<MappingReport, std::io::Error> {
+    let data = match json.pointer(data_pointer).and_then(|d| d.as_array()) {
+        Some(d) => d,
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Payload has no list of variables at '{}'", data_pointer),
+            ))
+        }
+    };
+
+    let mut skipped = Vec::new();
+    let devices = load_all_reporting(data, attributes_key, &mut skipped)?;
+    let mut report = MappingReport {
+        unmapped: unmapped_labels(data, attributes_key),
+        parse_failures: skipped.into_iter().collect(),
+        ..Default::default()
+    };
+    for variable in devices.iter().flat_map(|d| &d.variables) {
+        let label = variable.get("label").to_string();
+        if variable.get("value").parse::<f64>().is_err() {
+            report
+                .parse_failures
+                .insert((label.to_owned(), variable.get("value").to_string()));
+        }
+        report.mapped.insert(label);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn payload(variables: &[(&str, &str)]) -> serde_json::Value {
+        let data: Vec<serde_json::Value> = variables
+            .iter()
+            .map(|(label, value)| {
+                serde_json::json!({ "attributes": {
+                    "label": label,
+                    "value": value,
+                    "device_id": 1,
+                    "device_name": "cool-1",
+                }})
+            })
+            .collect();
+        serde_json::json!({ "data": data })
+    }
+
+    #[test]
+    fn reports_mapped_unmapped_and_unparseable_labels() {
+        let json = payload(&[
+            ("Humidity (%)", "45%"),
+            ("Temperature (C)", "n/a"),
+            ("Serial Number", "ABC123"),
+            ("Asset Tag", "rack 1"),
+        ]);
+        let report = test_mapping(&json, "/data", "attributes").unwrap();
+        assert_eq!(report.mapped.len(), 2);
+        assert_eq!(report.unmapped.len(), 2);
+        assert_eq!(report.parse_failures.len(), 1);
+        assert!(report
+            .parse_failures
+            .contains(&(String::from("Temperature (C)"), String::from("n/a"))));
+        let summary = report.summary();
+        assert!(summary.contains("Mapped labels (2):"));
+        assert!(summary.contains("Unmapped labels (2):"));
+        assert!(summary.contains("Parse failures (1):"));
+    }
+
+    #[test]
+    fn rejects_payloads_without_variables() {
+        let json = serde_json::json!({ "items": [] });
+        assert!(test_mapping(&json, "/data", "attributes").is_err());
+    }
+
+    #[test]
+    fn fails_strict_only_on_unmapped_labels() {
+        let json = payload(&[("Humidity (%)", "45%"), ("Serial Number", "ABC123")]);
+        let report = test_mapping(&json, "/data", "attributes").unwrap();
+        assert!(report.fails_strict());
+        assert!(!report.has_parse_failures());
+        assert_eq!(
+            report.to_json()["unmapped"],
+            serde_json::json!(["Serial Number"])
+        );
+
+        let json = payload(&[("Humidity (%)", "45%")]);
+        assert!(!test_mapping(&json, "/data", "attributes")
+            .unwrap()
+            .fails_strict());
+    }
+
+    #[test]
+    fn fails_on_unparseable_values_either_way() {
+        let json = payload(&[("Temperature (C)", "n/a")]);
+        let report = test_mapping(&json, "/data", "attributes").unwrap();
+        assert!(report.fails_strict());
+        assert!(report.has_parse_failures());
+    }
+}
@@ -1,3 +1,5 @@
 pub mod client;
 pub mod device;
+pub mod mapping;
+pub mod timestamp;
 pub mod variables;
@@ -0,0 +1,130 @@
+/// Parse a device timestamp given either as epoch seconds or as an ISO-8601
+/// date-time (e.g. `2023-05-25T12:00:00Z` or `2023-05-25T14:00:00.5+02:00`),
+/// normalized to epoch seconds
+pub fn parse_timestamp(value: &serde_json::Value) -> Option<f64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_f64(),
+        serde_json::Value::String(s) => match s.trim().parse::<f64>() {
+            Ok(epoch) => Some(epoch),
+            Err(_) => parse_iso8601(s.trim()),
+        },
+        _ => None,
+    }
+}
+
+fn parse_iso8601(s: &str) -> Option<f64> {
+    let (date, time) = s.split_once(['T', ' '])?;
+
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: i64 = date_parts.next()?.parse().ok()?;
+    let day: i64 = date_parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    // Split off the UTC offset, if any
+    let (time, offset) = if let Some(t) = time.strip_suffix(['Z', 'z']) {
+        (t, 0)
+    } else if let Some(i) = time.rfind(['+', '-']) {
+        let (t, o) = time.split_at(i);
+        let sign = if o.starts_with('-') { -1 } else { 1 };
+        // Offsets come as hh:mm, hhmm or just hh
+        let digits = &o[1..];
+        let (hours, minutes) = match digits.split_once(':') {
+            Some(parts) => parts,
+            None if digits.len() == 4 => digits.split_at(2),
+            None if digits.len() == 2 => (digits, "00"),
+            None => return None,
+        };
+        let hours = hours.parse::<u32>().ok()?;
+        let minutes = minutes.parse::<u32>().ok()?;
+        if minutes > 59 || hours * 60 + minutes > 14 * 60 {
+            return None;
+        }
+        (t, sign * (hours as i64 * 3600 + minutes as i64 * 60))
+    } else {
+        (time, 0)
+    };
+
+    let mut time_parts = time.splitn(3, ':');
+    let hour: i64 = time_parts.next()?.parse().ok()?;
+    let minute: i64 = time_parts.next()?.parse().ok()?;
+    let second: f64 = match time_parts.next() {
+        Some(s) => s.parse().ok()?,
+        None => 0.0,
+    };
+    if hour > 23 || minute > 59 || !(0.0..61.0).contains(&second) {
+        return None;
+    }
+
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 - offset;
+    Some(seconds as f64 + second)
+}
+
+/// Days since the Unix epoch of a proleptic Gregorian calendar date
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let yoe = year - era * 400;
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_epoch_seconds() {
+        assert_eq!(parse_timestamp(&json!(1685016000)), Some(1685016000.0));
+        assert_eq!(parse_timestamp(&json!(1685016000.5)), Some(1685016000.5));
+        assert_eq!(parse_timestamp(&json!(" 1685016000 ")), Some(1685016000.0));
+    }
+
+    #[test]
+    fn parses_iso8601() {
+        assert_eq!(
+            parse_timestamp(&json!("2023-05-25T12:00:00Z")),
+            Some(1685016000.0)
+        );
+        assert_eq!(
+            parse_timestamp(&json!("2023-05-25 12:00:00")),
+            Some(1685016000.0)
+        );
+        assert_eq!(
+            parse_timestamp(&json!("2023-05-25T14:00:00.5+02:00")),
+            Some(1685016000.5)
+        );
+        assert_eq!(
+            parse_timestamp(&json!("2023-05-25T10:30:00-01:30")),
+            Some(1685016000.0)
+        );
+    }
+
+    #[test]
+    fn parses_offsets_without_colon() {
+        assert_eq!(
+            parse_timestamp(&json!("2023-05-25T14:00:00+0200")),
+            Some(1685016000.0)
+        );
+        assert_eq!(
+            parse_timestamp(&json!("2023-05-25T14:00:00+02")),
+            Some(1685016000.0)
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(parse_timestamp(&json!("yesterday")), None);
+        assert_eq!(parse_timestamp(&json!("2023-13-25T12:00:00Z")), None);
+        assert_eq!(parse_timestamp(&json!("2023-05-25T25:00:00Z")), None);
+        assert_eq!(parse_timestamp(&json!("2023-05-25T12:00:00+1500")), None);
+        assert_eq!(parse_timestamp(&json!("2023-05-25T12:00:00+02:60")), None);
+        assert_eq!(parse_timestamp(&json!("2023-05-25T12:00:00+200")), None);
+        assert_eq!(parse_timestamp(&json!(null)), None);
+        assert_eq!(parse_timestamp(&json!(true)), None);
+    }
+}
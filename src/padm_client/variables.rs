@@ -1,10 +1,15 @@
-use log::error;
+use log::{error, warn};
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use serde_json::Map;
-use std::{collections::HashMap, sync::Mutex};
+use std::collections::HashMap;
 
-static PADM_VARIABLE_MAP: Lazy<Mutex<HashMap<&str, HashMap<&str, &str>>>> = Lazy::new(|| {
-    let map = HashMap::from([
+use crate::config::ExtraMetric;
+use crate::padm_client::timestamp::parse_timestamp;
+
+/// Built-in mappings of device labels to metrics
+static BUILTIN_VARIABLES: Lazy<HashMap<&str, HashMap<&str, &str>>> = Lazy::new(|| {
+    HashMap::from([
         (
             "Firmware Version",
             HashMap::from([
@@ -80,9 +85,11 @@ static PADM_VARIABLE_MAP: Lazy<Mutex<HashMap<&str, HashMap<&str, &str>>>> = Lazy
         (
             "Set Point Temperature (C)",
             HashMap::from([
-                ("name", "set_point_temperature"),
+                ("name", "set_point_temperature_celsius"),
+                ("legacy_name", "set_point_temperature"),
                 ("type", "gauge"),
                 ("help", "Set point temperature in celsius."),
+                ("unit", "C"),
             ]),
         ),
         (
@@ -96,25 +103,31 @@ static PADM_VARIABLE_MAP: Lazy<Mutex<HashMap<&str, HashMap<&str, &str>>>> = Lazy
         (
             "Return Air Temperature (C)",
             HashMap::from([
-                ("name", "return_air_temperature"),
+                ("name", "return_air_temperature_celsius"),
+                ("legacy_name", "return_air_temperature"),
                 ("type", "gauge"),
                 ("help", "Temperature of the return air in celsius."),
+                ("unit", "C"),
             ]),
         ),
         (
             "Remote Set Point Temperature (C)",
             HashMap::from([
-                ("name", "remote_set_point_temperature"),
+                ("name", "remote_set_point_temperature_celsius"),
+                ("legacy_name", "remote_set_point_temperature"),
                 ("type", "gauge"),
                 ("help", "Set point temperature of the remote sensor."),
+                ("unit", "C"),
             ]),
         ),
         (
             "Temperature (C)",
             HashMap::from([
-                ("name", "temperature"),
+                ("name", "temperature_celsius"),
+                ("legacy_name", "temperature"),
                 ("type", "gauge"),
                 ("help", "Current detected temperature."),
+                ("unit", "C"),
             ]),
         ),
         (
@@ -152,34 +165,138 @@ static PADM_VARIABLE_MAP: Lazy<Mutex<HashMap<&str, HashMap<&str, &str>>>> = Lazy
         (
             "Temperature (C) Low Critical Threshold",
             HashMap::from([
-                ("name", "temp_low_crit_threshold"),
+                ("name", "temp_low_crit_threshold_celsius"),
+                ("legacy_name", "temp_low_crit_threshold"),
                 ("type", "gauge"),
                 ("help", "Critically low temperature."),
+                ("unit", "C"),
             ]),
         ),
         (
             "Temperature (C) High Critical Threshold",
             HashMap::from([
-                ("name", "temp_high_crit_threshold"),
+                ("name", "temp_high_crit_threshold_celsius"),
+                ("legacy_name", "temp_high_crit_threshold"),
                 ("type", "gauge"),
                 ("help", "Critically high temperature."),
+                ("unit", "C"),
+            ]),
+        ),
+        (
+            "Deadband (C)",
+            HashMap::from([
+                ("name", "setpoint_deadband_celsius"),
+                ("kind", "deadband"),
+                ("type", "gauge"),
+                (
+                    "help",
+                    "Deadband around the set point temperature in celsius.",
+                ),
+                ("unit", "C"),
+                ("relative", "true"),
+            ]),
+        ),
+        (
+            "Hysteresis (C)",
+            HashMap::from([
+                ("name", "setpoint_deadband_celsius"),
+                ("kind", "hysteresis"),
+                ("type", "gauge"),
+                (
+                    "help",
+                    "Deadband around the set point temperature in celsius.",
+                ),
+                ("unit", "C"),
+                ("relative", "true"),
+            ]),
+        ),
+        (
+            "Humidity (%)",
+            HashMap::from([
+                ("name", "humidity_percent"),
+                ("type", "gauge"),
+                ("help", "Current detected relative humidity in percent."),
+                ("unit", "%"),
+            ]),
+        ),
+        (
+            "Battery Capacity (%)",
+            HashMap::from([
+                ("name", "battery_capacity_percent"),
+                ("type", "gauge"),
+                ("help", "Remaining battery capacity in percent."),
+                ("unit", "%"),
+            ]),
+        ),
+        (
+            "Input Voltage (V)",
+            HashMap::from([
+                ("name", "input_voltage_volts"),
+                ("type", "gauge"),
+                ("help", "Input voltage in volts."),
+                ("unit", "V"),
             ]),
         ),
-    ]);
-    Mutex::new(map)
+        (
+            "Output Current (A)",
+            HashMap::from([
+                ("name", "output_current_amperes"),
+                ("type", "gauge"),
+                ("help", "Output current in amperes."),
+                ("unit", "A"),
+            ]),
+        ),
+        (
+            "Active Power (W)",
+            HashMap::from([
+                ("name", "active_power_watts"),
+                ("type", "gauge"),
+                ("help", "Active power in watts."),
+                ("unit", "W"),
+            ]),
+        ),
+        (
+            "Energy (kWh)",
+            HashMap::from([
+                ("name", "energy_kilowatt_hours"),
+                ("type", "counter"),
+                ("help", "Accumulated energy in kilowatt hours."),
+                ("unit", "kWh"),
+            ]),
+        ),
+    ])
 });
 
+/// Mappings in effect, the built-in ones along with those registered from the config
+static PADM_VARIABLE_MAP: Lazy<Mutex<HashMap<String, HashMap<&str, String>>>> =
+    Lazy::new(|| Mutex::new(builtin_variables()));
+
+fn builtin_variables() -> HashMap<String, HashMap<&'static str, String>> {
+    BUILTIN_VARIABLES
+        .iter()
+        .map(|(label, var)| {
+            let var = var.iter().map(|(k, v)| (*k, v.to_string())).collect();
+            (label.to_string(), var)
+        })
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct Variable {
+    label: String,
     name: String,
     vtype: String,
     help: String,
     value: String,
     labels: Option<HashMap<String, String>>,
+    timestamp: Option<f64>,
+    legacy_name: Option<String>,
+    unit: Option<String>,
 }
 impl Variable {
     pub fn get(&self, field: &str) -> &str {
         match field {
+            "label" => &self.label,
             "name" => &self.name,
             "type" => &self.vtype,
             "help" => &self.help,
@@ -187,9 +304,50 @@ impl Variable {
             _ => "",
         }
     }
+    pub fn set_value(&mut self, value: String) {
+        self.value = value;
+    }
     pub fn labels(&self) -> &Option<HashMap<String, String>> {
         &self.labels
     }
+    /// Unit suffix the device reports values with
+    pub fn unit(&self) -> Option<&str> {
+        self.unit.as_deref()
+    }
+    /// Name the metric was exported under before being renamed
+    pub fn legacy_name(&self) -> Option<&str> {
+        self.legacy_name.as_deref()
+    }
+    /// Time the device reported for this value, in epoch seconds
+    pub fn timestamp(&self) -> Option<f64> {
+        self.timestamp
+    }
+}
+
+/// Map device labels to the extra metrics of a config, replacing those of any
+/// previous config and going back to the built-in mapping of labels no longer
+/// mapped. Returns the extra metrics that replace another mapping.
+pub fn register_extra_metrics(extra_metrics: &[ExtraMetric]) -> Vec<&ExtraMetric> {
+    let mut map = builtin_variables();
+    let mut replacing = Vec::new();
+    for metric in extra_metrics {
+        let mut var = HashMap::from([
+            ("name", metric.name().to_string()),
+            ("type", metric.mtype().as_str().to_string()),
+            ("help", metric.help().to_string()),
+        ]);
+        if let Some(unit) = metric.unit() {
+            var.insert("unit", unit.to_string());
+        }
+        if metric.is_enum() {
+            var.insert("enum", String::from("true"));
+        }
+        if map.insert(metric.label().to_string(), var).is_some() {
+            replacing.push(metric);
+        }
+    }
+    *PADM_VARIABLE_MAP.lock() = map;
+    replacing
 }
 
 /// Mutate the variable's value if needed
@@ -304,18 +462,26 @@ pub fn mutate_variable<'a>(
     }
 }
 
-pub fn unpack_variable(data: &Map<String, serde_json::Value>) -> Variable {
-    let map = PADM_VARIABLE_MAP.lock().unwrap();
-    let extract = |field: &str| -> String {
-        // Get the map containing the label
-        let var = match map.get(&data["label"].as_str().unwrap()) {
-            Some(s) => s,
-            None => {
-                error!("Key 'label' not found in devices data!");
-                return String::new();
-            }
-        };
+/// Strip a trailing unit suffix (e.g. `45%` or `230 V`) from a raw value
+pub fn strip_unit<'a>(value: &'a str, unit: &str) -> &'a str {
+    let value = value.trim();
+    match value.strip_suffix(unit) {
+        Some(v) => v.trim_end().trim_end_matches('°').trim_end(),
+        None => value,
+    }
+}
 
+/// Unpack a variable from its attributes, or explain why it can't be
+pub fn unpack_variable(data: &Map<String, serde_json::Value>) -> Result<Variable, String> {
+    let label = data
+        .get("label")
+        .and_then(|l| l.as_str())
+        .ok_or("missing or non-string 'label'")?;
+    let map = PADM_VARIABLE_MAP.lock();
+    let var = map
+        .get(label)
+        .ok_or_else(|| format!("unknown label '{}'", label))?;
+    let extract = |field: &str| -> String {
         match var.get(field) {
             Some(s) => s.to_string(),
             None => {
@@ -327,21 +493,224 @@ pub fn unpack_variable(data: &Map<String, serde_json::Value>) -> Variable {
 
     let var_name = extract("name");
 
-    let (value, labels) = mutate_variable(&var_name, data["value"].as_str().unwrap());
+    // Some firmware sends numbers rather than strings, or only a raw value
+    let value_text = ["value", "raw_value"]
+        .iter()
+        .find_map(|field| match data.get(*field) {
+            Some(serde_json::Value::String(s)) => Some(s.to_owned()),
+            Some(serde_json::Value::Number(n)) => Some(n.to_string()),
+            _ => None,
+        })
+        .ok_or_else(|| format!("missing or non-scalar 'value' for '{}'", label))?;
+    let mut raw_value = value_text.as_str();
+    if let Some(unit) = var.get("unit") {
+        raw_value = strip_unit(raw_value, unit);
+    }
+
+    // User-defined enums keep the raw text as a label
+    let (value, labels) = match var.get("enum").is_some_and(|e| e == "true") {
+        true => (
+            "1",
+            Some(HashMap::from([(
+                String::from("state"),
+                raw_value.to_string(),
+            )])),
+        ),
+        false => mutate_variable(&var_name, raw_value),
+    };
+
+    // Variables sharing a metric name are told apart by their kind
+    let labels = match var.get("kind") {
+        Some(kind) => {
+            let mut labels = labels.unwrap_or_default();
+            labels.insert(String::from("kind"), kind.to_string());
+            Some(labels)
+        }
+        None => labels,
+    };
+
+    let unit = var.get("unit").map(|s| s.to_string());
+
+    let legacy_name = var.get("legacy_name").map(|s| s.to_string());
 
-    Variable {
+    let timestamp = match data.get("timestamp") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(t) => {
+            let parsed = parse_timestamp(t);
+            if parsed.is_none() {
+                warn!("Ignoring unparseable timestamp {} for {}", t, var_name);
+            }
+            parsed
+        }
+    };
+
+    Ok(Variable {
+        label: label.to_string(),
         name: var_name.to_owned(),
         vtype: extract("type"),
         help: extract("help"),
         value: value.to_owned(),
         labels,
+        timestamp,
+        legacy_name,
+        unit,
+    })
+}
+
+/// Conversion of a metric into SI base units
+pub struct SiConversion {
+    pub name: String,
+    pub help: String,
+    pub unit: &'static str,
+    factor: f64,
+    offset: f64,
+}
+impl SiConversion {
+    pub fn apply(&self, value: f64) -> f64 {
+        value * self.factor + self.offset
     }
 }
 
+/// How to express a variable in SI base units, if it isn't already
+pub fn si_conversion(variable: &Variable) -> Option<SiConversion> {
+    let (from, to, unit, factor, offset) = match variable.unit()? {
+        "C" => ("celsius", "kelvin", "K", 1.0, 273.15),
+        "kWh" => ("kilowatt_hours", "joules", "J", 3_600_000.0, 0.0),
+        _ => return None,
+    };
+    let name = variable.get("name");
+    let base = name.strip_suffix(from)?;
+
+    // Temperature differences don't shift with the scale's zero point
+    let map = PADM_VARIABLE_MAP.lock();
+    let relative = map.values().any(|var| {
+        var.get("name").is_some_and(|n| n == name)
+            && var.get("relative").is_some_and(|r| r == "true")
+    });
+
+    Some(SiConversion {
+        name: format!("{}{}", base, to),
+        help: variable
+            .get("help")
+            .replace(from, to)
+            .replace(&from.replace('_', " "), to),
+        unit,
+        factor,
+        offset: if relative { 0.0 } else { offset },
+    })
+}
+
+/// Names of all metrics variables can be mapped to
+pub fn known_metric_names() -> Vec<String> {
+    let map = PADM_VARIABLE_MAP.lock();
+    map.values()
+        .filter_map(|var| var.get("name").cloned())
+        .collect()
+}
+
 pub fn is_metric(data: &Map<String, serde_json::Value>) -> bool {
-    let map = PADM_VARIABLE_MAP.lock().unwrap();
-    match map.get(&data["label"].as_str().unwrap()) {
-        Some(..) => true,
-        None => false,
+    let map = PADM_VARIABLE_MAP.lock();
+    data.get("label")
+        .and_then(|l| l.as_str())
+        .is_some_and(|l| map.contains_key(l))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(label: &str, value: &str) -> Map<String, serde_json::Value> {
+        serde_json::json!({ "label": label, "value": value })
+            .as_object()
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn strips_known_units() {
+        assert_eq!(strip_unit("45%", "%"), "45");
+        assert_eq!(strip_unit("230V", "V"), "230");
+        assert_eq!(strip_unit("23.5 °C", "C"), "23.5");
+        assert_eq!(strip_unit("230", "V"), "230");
+    }
+
+    #[test]
+    fn unpacks_percentages() {
+        let humidity = unpack_variable(&attributes("Humidity (%)", "45%")).unwrap();
+        assert_eq!(humidity.get("value"), "45");
+        let battery = unpack_variable(&attributes("Battery Capacity (%)", "100 %")).unwrap();
+        assert_eq!(battery.get("value"), "100");
+    }
+
+    #[test]
+    fn tells_deadband_and_hysteresis_apart() {
+        let deadband = unpack_variable(&attributes("Deadband (C)", "1.5")).unwrap();
+        let hysteresis = unpack_variable(&attributes("Hysteresis (C)", "0.5")).unwrap();
+        assert_eq!(deadband.get("name"), hysteresis.get("name"));
+        assert_eq!(deadband.get("value"), "1.5");
+        assert_ne!(deadband.labels(), hysteresis.labels());
+    }
+
+    #[test]
+    fn keeps_values_without_units_strict() {
+        let temperature = unpack_variable(&attributes("Temperature (C)", "45%")).unwrap();
+        assert!(temperature.get("value").parse::<f64>().is_err());
+    }
+
+    #[test]
+    fn replaces_extra_metrics_on_reload() {
+        let extra_metrics: Vec<ExtraMetric> = toml::from_str::<HashMap<String, _>>(
+            r#"extra_metrics = [
+                { label = "LCD Display Units (Cooling)", name = "units", help = "Units.", enum = true },
+                { label = "Door Sensor", name = "door_open", help = "Door." },
+            ]"#,
+        )
+        .unwrap()
+        .remove("extra_metrics")
+        .unwrap();
+        let replacing = register_extra_metrics(&extra_metrics);
+        assert_eq!(replacing.len(), 1);
+        assert_eq!(replacing[0].name(), "units");
+        let units = unpack_variable(&attributes("LCD Display Units (Cooling)", "Metric")).unwrap();
+        assert_eq!(units.get("name"), "units");
+        assert!(unpack_variable(&attributes("Door Sensor", "1")).is_ok());
+
+        // Mappings of the previous config are dropped, built-in ones come back
+        assert!(register_extra_metrics(&[]).is_empty());
+        let units = unpack_variable(&attributes("LCD Display Units (Cooling)", "Metric")).unwrap();
+        assert_eq!(units.get("name"), "lcd_display_units");
+        assert!(unpack_variable(&attributes("Door Sensor", "1")).is_err());
+    }
+
+    #[test]
+    fn converts_celsius_to_kelvin() {
+        let temperature = unpack_variable(&attributes("Temperature (C)", "21.5")).unwrap();
+        let si = si_conversion(&temperature).unwrap();
+        assert_eq!(si.name, "temperature_kelvin");
+        assert_eq!(si.unit, "K");
+        assert!((si.apply(21.5) - 294.65).abs() < 1e-9);
+        assert!(si.apply(-273.15).abs() < 1e-9);
+
+        // Differences between temperatures are the same in both scales
+        let deadband = unpack_variable(&attributes("Deadband (C)", "1.5")).unwrap();
+        let si = si_conversion(&deadband).unwrap();
+        assert_eq!(si.name, "setpoint_deadband_kelvin");
+        assert_eq!(si.apply(1.5), 1.5);
+    }
+
+    #[test]
+    fn converts_kilowatt_hours_to_joules() {
+        let energy = unpack_variable(&attributes("Energy (kWh)", "2.5")).unwrap();
+        let si = si_conversion(&energy).unwrap();
+        assert_eq!(si.name, "energy_joules");
+        assert_eq!(si.unit, "J");
+        assert_eq!(si.help, "Accumulated energy in joules.");
+        assert_eq!(si.apply(2.5), 9_000_000.0);
+    }
+
+    #[test]
+    fn keeps_other_units() {
+        let humidity = unpack_variable(&attributes("Humidity (%)", "45%")).unwrap();
+        assert!(si_conversion(&humidity).is_none());
     }
 }
@@ -1,4 +1,7 @@
 pub mod probe;
+pub mod protobuf;
+pub mod push;
+#[allow(clippy::module_inception)]
 pub mod server;
 
 pub use server::*;
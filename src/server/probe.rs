@@ -1,15 +1,50 @@
-use log::error;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-use tokio::runtime::Runtime;
-
-use crate::config;
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{mpsc, oneshot, Notify};
+
+use crate::config::{self, NaPolicy, TimestampPolicy};
 use crate::padm_client::{
     client::PADMClient,
-    device::{load_all_from, Device},
+    device::{count_unmapped, enum_values, load_all_from, Device, DEVICE_ID_CONFLICTS},
+    variables::si_conversion,
 };
+use crate::server::push::{Pusher, PUSH_FAILURES};
+
+/// Number of client tasks currently running
+static ACTIVE_TASKS: AtomicU64 = AtomicU64::new(0);
+
+/// Counts a client task as active for as long as it is alive, even if it panics
+struct TaskGuard;
+impl TaskGuard {
+    fn new() -> TaskGuard {
+        ACTIVE_TASKS.fetch_add(1, Ordering::Relaxed);
+        TaskGuard
+    }
+}
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        ACTIVE_TASKS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Resident set size of the exporter process in bytes
+#[cfg(target_os = "linux")]
+fn resident_memory_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory_bytes() -> Option<u64> {
+    None
+}
 
 #[derive(Debug, Clone)]
 struct Metric {
@@ -21,145 +56,1373 @@ struct Metric {
 
 #[derive(Debug, Clone)]
 struct DeviceMetric {
-    device: String,
+    device: Option<String>,
     value: String,
     labels: HashMap<String, String>,
+    /// Explicit timestamp of the sample in milliseconds since the epoch
+    timestamp: Option<i64>,
+}
+
+/// Latest known state of a single endpoint
+#[derive(Debug, Clone, Default)]
+struct EndpointState {
+    host: String,
+    devices: Vec<Device>,
+    auth_permanently_failed: bool,
+    auth_throttled: bool,
+    client_rebuilds: u64,
+    retry_budget_exhausted: u64,
+    scrape_errors: u64,
+    auth_errors: u64,
+    last_scrape_duration: Option<Duration>,
+    omit_device_label: bool,
+    last_success: Option<Instant>,
+    decode_duration: Option<Duration>,
+    known_devices: HashSet<String>,
+    expected_devices: Option<usize>,
+    successful_probes: u32,
+    warmup_probes: u32,
+    cert_expiry: Option<i64>,
+    scrape_durations: Histogram,
+    scraped_at: Option<SystemTime>,
+    last_probe_ok: bool,
+    enums: Enums,
+    summary_log: bool,
+    counter_reset_protection: bool,
+}
+
+/// Distribution of observed values over fixed buckets
+#[derive(Debug, Clone, Default)]
+struct Histogram {
+    bounds: Vec<f64>,
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn new(bounds: &[f64]) -> Histogram {
+        let mut bounds = bounds.to_vec();
+        bounds.retain(|b| b.is_finite());
+        bounds.sort_by(|a, b| a.total_cmp(b));
+        bounds.dedup();
+        Histogram {
+            counts: vec![0; bounds.len()],
+            bounds,
+            sum: 0.0,
+            count: 0,
+        }
+    }
+    fn observe(&mut self, value: f64) {
+        if let Some(i) = self.bounds.iter().position(|b| value <= *b) {
+            self.counts[i] += 1;
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+    /// Add the observations of a histogram with the same buckets
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.sum += other.sum;
+        self.count += other.count;
+    }
+}
+
+/// Rendered device and exporter metrics along with the number of device samples
+#[derive(Debug, Default)]
+pub struct Exposition {
+    pub body: String,
+    pub self_body: String,
+    pub device_samples: usize,
+    pub ready: bool,
+    /// Values reported for enum variables, by target
+    pub enums: HashMap<String, Enums>,
+}
+
+/// All values reported for each enum variable, by variable label
+pub type Enums = BTreeMap<String, BTreeSet<String>>;
+
+/// Request to replace the credentials of a running client
+pub struct Rotation {
+    pub username: String,
+    pub password: String,
+    /// Outcome of authenticating with the new credentials
+    pub result: oneshot::Sender<Result<(), String>>,
+}
+
+/// Channels to request credential rotations, by endpoint host
+pub type Rotations = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Rotation>>>>;
+
+/// Devices loaded from a single probe along with details about the probe
+struct ProbeResult {
+    devices: Vec<Device>,
+    unmapped: usize,
+    decode_duration: Duration,
+    enums: Enums,
+}
+
+/// Fetch and decode a single page of variables, returning it with the time spent decoding
+async fn get_page_from(
+    client: &PADMClient,
+    endpoint: &config::Endpoint,
+    path: &str,
+) -> Result<(serde_json::Value, Duration), anyhow::Error> {
+    let mut response = client
+        .do_request(endpoint.probe_method(), path, endpoint.probe_body())
+        .await?
+        .error_for_status()?;
+
+    // Read the body in chunks so an oversized response can't be buffered unbounded
+    let max_bytes = endpoint.max_api_response_bytes();
+    let mut body: Vec<u8> = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > max_bytes {
+            return Err(anyhow::anyhow!(
+                "Response exceeded the maximum size of {} bytes",
+                max_bytes
+            ));
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let decode_start = Instant::now();
+    let json: serde_json::Value = serde_json::from_slice(&body)?;
+    let decode_duration = decode_start.elapsed();
+    // Some firmware reports errors in the body of a 200 response
+    match json.get(endpoint.error_field()) {
+        None | Some(serde_json::Value::Null) => (),
+        Some(serde_json::Value::String(e)) => {
+            return Err(anyhow::anyhow!("Device returned an error: {}", e))
+        }
+        Some(e) => return Err(anyhow::anyhow!("Device returned an error: {}", e)),
+    }
+    if let Some(predicate) = endpoint.success_predicate() {
+        if !predicate.matches(&json) {
+            return Err(anyhow::anyhow!(
+                "Response did not satisfy the success predicate"
+            ));
+        }
+    }
+    Ok((json, decode_duration))
+}
+
+/// Path of the page following `json`, if the response is paginated
+fn next_page(json: &serde_json::Value, path: &str) -> Option<String> {
+    // Follow an explicit link to the next page
+    if let Some(next) = json.pointer("/links/next").and_then(|n| n.as_str()) {
+        return match reqwest::Url::parse(next) {
+            Ok(url) => Some(match url.query() {
+                Some(query) => format!("{}?{}", url.path(), query),
+                None => url.path().to_string(),
+            }),
+            Err(_) => Some(next.to_string()),
+        };
+    }
+    // Otherwise work out the next page number from the totals
+    let page = json.pointer("/meta/page").and_then(|p| p.as_u64())?;
+    let total = json.pointer("/meta/total_pages").and_then(|t| t.as_u64())?;
+    if page >= total {
+        return None;
+    }
+    let base = path.split_once('?').map_or(path, |(base, _)| base);
+    Some(format!("{}?page={}", base, page + 1))
 }
 
-async fn get_devices_from(client: &PADMClient) -> Result<Vec<Device>, anyhow::Error> {
-    let response = client.do_get("/api/variables").await;
-    match response
-        .and_then(|r| r.error_for_status())
-        .map_err(|e| anyhow::Error::from(e))
-    {
-        Ok(r) => r.text().await.map_err(|e| e.into()).and_then(|s| {
-            let json = serde_json::from_str(&s).map_err(|e| anyhow::Error::from(e))?;
-            load_all_from(&json).map_err(|e| e.into())
-        }),
-        Err(e) => Err(e),
+#[cfg_attr(
+    feature = "trace",
+    tracing::instrument(name = "probe", skip_all, fields(target = %client.host()))
+)]
+async fn get_devices_from(
+    client: &PADMClient,
+    endpoint: &config::Endpoint,
+) -> Result<ProbeResult, anyhow::Error> {
+    let mut path = String::from("/api/variables");
+    let mut data: Vec<serde_json::Value> = Vec::new();
+    let mut decode_duration = Duration::ZERO;
+    let mut pages = 0;
+
+    // Collect variables from every page before loading devices
+    loop {
+        let (json, page_duration) = get_page_from(client, endpoint, &path).await?;
+        decode_duration += page_duration;
+        pages += 1;
+        match json
+            .pointer(endpoint.data_pointer())
+            .and_then(|d| d.as_array())
+        {
+            Some(page_data) => data.extend(page_data.iter().cloned()),
+            None => {
+                return Err(anyhow::anyhow!(
+                    "Response has no list of variables at '{}'",
+                    endpoint.data_pointer()
+                ))
+            }
+        }
+
+        match next_page(&json, &path) {
+            Some(next) if pages >= endpoint.max_pages() => {
+                warn!(
+                    "Endpoint {} has more than {} pages of variables, ignoring {} and beyond",
+                    endpoint.host(),
+                    endpoint.max_pages(),
+                    next
+                );
+                break;
+            }
+            Some(next) => path = next,
+            None => break,
+        }
     }
+
+    Ok(ProbeResult {
+        devices: load_all_from(&data, endpoint.attributes_key())?,
+        unmapped: count_unmapped(&data, endpoint.attributes_key()),
+        decode_duration,
+        enums: enum_values(&data, endpoint.attributes_key()),
+    })
 }
 
-fn format_output_from_devices(devices: &Vec<Device>) -> Result<String, std::io::Error> {
+#[cfg_attr(feature = "trace", tracing::instrument(name = "update", skip_all))]
+fn format_output_from_states(
+    states: &[EndpointState],
+    config: &config::Config,
+) -> Result<Exposition, std::io::Error> {
+    let transforms = config.label_transforms();
     let mut body: String = String::new();
+    let mut device_samples: usize = 0;
+    let mut target_samples: HashMap<&str, usize> = HashMap::new();
     let mut all_metrics: Vec<Metric> = Vec::new();
+    let mut in_maintenance: Vec<(&str, &str)> = Vec::new();
 
-    for device in devices {
-        for variable in &device.variables {
-            let name = variable.get("name").to_string();
-            let value = variable.get("value").to_string();
+    for state in states {
+        for device in &state.devices {
+            // Keep intentionally idle devices from tripping alerts
+            if let Some(indicator) = config.maintenance_indicator() {
+                if device.variables.iter().any(|v| indicator.matches(v)) {
+                    in_maintenance.push((state.host.as_str(), device.name.as_str()));
+                    continue;
+                }
+            }
+            for variable in &device.variables {
+                let name = variable.get("name").to_string();
+                if config.metric_disabled(&name) {
+                    continue;
+                }
+                let si = match config.si_base_units() {
+                    true => si_conversion(variable),
+                    false => None,
+                };
 
-            if let Some(metric) = all_metrics.iter_mut().find(|x| x.name == name) {
-                metric.metrics.push(DeviceMetric {
-                    device: device.name.to_owned(),
+                let value = variable.get("value");
+                let value = if let Some(policy) = config.na_policy(value) {
+                    match policy {
+                        NaPolicy::Skip => continue,
+                        NaPolicy::Zero => String::from("0"),
+                        NaPolicy::Nan => String::from("NaN"),
+                    }
+                } else if let Ok(v) = value.parse::<f64>() {
+                    let scaled = config.metric_scale(&name).map(|scale| v * scale);
+                    match (&si, scaled) {
+                        (Some(si), _) => si.apply(scaled.unwrap_or(v)).to_string(),
+                        (None, Some(scaled)) => scaled.to_string(),
+                        (None, None) => value.to_string(),
+                    }
+                } else if config.unparseable_as_nan() {
+                    String::from("NaN")
+                } else {
+                    debug!(
+                        "Skipping unparseable value '{}' for {} on device {}",
+                        value, name, device.name
+                    );
+                    continue;
+                };
+                let mut labels = device.labels.to_owned();
+                // Keep the label set of a metric the same across devices, even
+                // those not reporting a type
+                if config.emit_device_type_label() {
+                    labels.insert(String::from("device_type"), device.device_type.to_owned());
+                }
+                if let Some(l) = variable.labels() {
+                    labels.extend(l.to_owned());
+                }
+                for (k, v) in labels.iter_mut() {
+                    *v = transforms.iter().fold(v.to_owned(), |v, t| t.apply(k, &v));
+                }
+                let device_metric = DeviceMetric {
+                    device: match state.omit_device_label {
+                        true => None,
+                        false => Some(
+                            transforms
+                                .iter()
+                                .fold(device.name.to_owned(), |v, t| t.apply("device", &v)),
+                        ),
+                    },
                     value,
-                    labels: match variable.labels() {
-                        Some(l) => l.to_owned(),
-                        None => HashMap::new(),
+                    labels,
+                    timestamp: match config.timestamp_policy() {
+                        TimestampPolicy::None => None,
+                        TimestampPolicy::Device => {
+                            variable.timestamp().map(|t| (t * 1000.0) as i64)
+                        }
+                        TimestampPolicy::Scrape => state
+                            .scraped_at
+                            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                            .map(|d| d.as_millis() as i64),
                     },
-                });
-            } else {
-                let metric = Metric {
-                    name,
-                    mtype: variable.get("type").to_string(),
-                    help: variable.get("help").to_string(),
-                    metrics: vec![DeviceMetric {
-                        device: device.name.to_owned(),
-                        value,
-                        labels: match variable.labels() {
-                            Some(l) => l.to_owned(),
-                            None => HashMap::new(),
-                        },
-                    }],
                 };
 
-                all_metrics.push(metric);
+                // Also emit renamed metrics under their old name while migrating,
+                // unless the value was converted away from the old unit
+                let name = match &si {
+                    Some(si) => si.name.to_owned(),
+                    None => name,
+                };
+                let mut names = vec![name];
+                if config.emit_legacy_aliases() && si.is_none() {
+                    if let Some(legacy_name) = variable.legacy_name() {
+                        names.push(legacy_name.to_string());
+                    }
+                }
+
+                for name in names {
+                    *target_samples.entry(state.host.as_str()).or_insert(0) += 1;
+                    if let Some(metric) = all_metrics.iter_mut().find(|x| x.name == name) {
+                        metric.metrics.push(device_metric.to_owned());
+                    } else {
+                        let mtype = variable.get("type").to_string();
+                        let unit = match &si {
+                            Some(si) => si.unit,
+                            None => variable.unit().unwrap_or(""),
+                        };
+                        let help = config
+                            .help_override(&name, &mtype, unit)
+                            .unwrap_or_else(|| match &si {
+                                Some(si) => si.help.to_owned(),
+                                None => variable.get("help").to_string(),
+                            });
+                        let metric = Metric {
+                            name,
+                            mtype,
+                            help,
+                            metrics: vec![device_metric.to_owned()],
+                        };
+
+                        all_metrics.push(metric);
+                    }
+                }
             }
         }
     }
 
+    // Catalog of the metrics being exposed, for tooling that reads metadata from labels
+    let metadata: Vec<(String, String)> = match config.emit_metric_metadata() {
+        true => all_metrics
+            .iter()
+            .map(|m| {
+                (
+                    format!(
+                        "name=\"{}{}\",type=\"{}\",help=\"{}\"",
+                        config.metric_prefix(),
+                        m.name,
+                        m.mtype,
+                        m.help.replace('\\', "\\\\").replace('"', "\\\"")
+                    ),
+                    String::from("1"),
+                )
+            })
+            .collect(),
+        false => Vec::new(),
+    };
+
+    let prefix = config.metric_prefix();
     for metric in all_metrics {
-        body.push_str(format!("# HELP {} {}\n", metric.name, metric.help).as_str());
-        body.push_str(format!("# TYPE {} {}\n", metric.name, metric.mtype).as_str());
+        let name = format!("{}{}", prefix, metric.name);
+        body.push_str(format!("# HELP {} {}\n", name, metric.help).as_str());
+        body.push_str(format!("# TYPE {} {}\n", name, metric.mtype).as_str());
 
         for device_metric in metric.metrics {
-            let mut inner: String = format!("device=\"{}\"", device_metric.device);
-            for label in device_metric.labels {
-                let (k, v) = label;
-                inner = format!("{},{}=\"{}\"", inner, k, v);
+            device_samples += 1;
+            let mut inner: Vec<String> = Vec::new();
+            if let Some(device) = device_metric.device {
+                inner.push(format!("device=\"{}\"", device));
+            }
+            for (k, v) in device_metric.labels {
+                inner.push(format!("{}=\"{}\"", k, v));
             }
+            let inner = inner.join(",");
+            let timestamp = match device_metric.timestamp {
+                Some(t) => format!(" {}", t),
+                None => String::new(),
+            };
             body.push_str(
                 format!(
-                    "padm_{}{{{}}} {}\n",
-                    metric.name, inner, device_metric.value,
+                    "{}{{{}}} {}{}\n",
+                    name, inner, device_metric.value, timestamp,
                 )
                 .as_str(),
             );
         }
     }
-    Ok(body)
-}
 
-pub async fn run(config: config::Config, body: Arc<Mutex<String>>) {
-    let mut device_arcs = Vec::new();
+    // Scrapes of the exporter succeed even when a device is down, so alert on
+    // this rather than `up`. Endpoints without a running client count as down.
+    push_exporter_metric(
+        &mut body,
+        &format!("{}probe_success", prefix),
+        "gauge",
+        "Whether the last probe of the target succeeded.",
+        &config
+            .endpoints()
+            .iter()
+            .map(|e| {
+                let host = e.host();
+                let ok = states.iter().any(|s| s.host == host && s.last_probe_ok);
+                (format!("target=\"{}\"", host), (ok as u8).to_string())
+            })
+            .collect::<Vec<_>>(),
+    );
 
-    // Spawn client threads
-    for endpoint in config.endpoints() {
-        let client = PADMClient::new(
-            endpoint.host().as_str(),
-            endpoint.scheme(),
-            endpoint.tls_insecure(),
-            endpoint.interval(),
-            endpoint.username(),
-            endpoint.password(),
+    if !metadata.is_empty() {
+        push_exporter_metric(
+            &mut body,
+            &format!("{}metric_metadata", prefix),
+            "gauge",
+            "Metadata of each exposed metric, always 1.",
+            &metadata,
         );
+    }
 
-        let arc = Arc::new(Mutex::new(Vec::new()));
-        let arc_clone = arc.clone();
-        let current = thread::current();
+    if config.maintenance_indicator().is_some() {
+        push_exporter_metric(
+            &mut body,
+            &format!("{}device_in_maintenance", prefix),
+            "gauge",
+            "Devices whose metrics are suppressed because they are in maintenance.",
+            &in_maintenance
+                .iter()
+                .map(|(host, device)| {
+                    (
+                        format!("target=\"{}\",device=\"{}\"", host, device),
+                        String::from("1"),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        );
+    }
 
-        thread::spawn(move || {
-            let rt = Runtime::new().unwrap();
-            rt.block_on(async move { client_run(client, arc_clone, current).await });
-            loop {
-                thread::park();
-            }
-        });
+    let mut self_body: String = String::new();
+    push_exporter_metric(
+        &mut self_body,
+        "padm_device_id_conflicts_total",
+        "counter",
+        "Number of times a device ID was reported with conflicting names.",
+        &[(
+            String::new(),
+            DEVICE_ID_CONFLICTS.load(Ordering::Relaxed).to_string(),
+        )],
+    );
+    if config.pushgateway_url().is_some() {
+        push_exporter_metric(
+            &mut self_body,
+            "padm_push_failures_total",
+            "counter",
+            "Number of failed pushes to the Pushgateway.",
+            &[(
+                String::new(),
+                PUSH_FAILURES.load(Ordering::Relaxed).to_string(),
+            )],
+        );
+    }
+    push_exporter_metric(
+        &mut self_body,
+        "padm_exporter_build_info",
+        "gauge",
+        "Version of the exporter and what it was built with.",
+        &[(
+            format!(
+                "version=\"{}\",rustc=\"{}\",commit=\"{}\"",
+                env!("CARGO_PKG_VERSION"),
+                env!("PADM_RUSTC_VERSION"),
+                env!("PADM_GIT_COMMIT")
+            ),
+            String::from("1"),
+        )],
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_exporter_targets_configured",
+        "gauge",
+        "Number of targets in the configuration.",
+        &[(String::new(), config.endpoints().len().to_string())],
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_exporter_targets_active",
+        "gauge",
+        "Number of targets with a running client.",
+        &[(String::new(), states.len().to_string())],
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_exporter_active_tasks",
+        "gauge",
+        "Number of client tasks currently running.",
+        &[(
+            String::new(),
+            ACTIVE_TASKS.load(Ordering::Relaxed).to_string(),
+        )],
+    );
+    if let Some(rss) = resident_memory_bytes() {
+        push_exporter_metric(
+            &mut self_body,
+            "padm_exporter_resident_memory_bytes",
+            "gauge",
+            "Resident memory size of the exporter in bytes.",
+            &[(String::new(), rss.to_string())],
+        );
+    }
+    push_exporter_metric(
+        &mut self_body,
+        "padm_target_interval_seconds",
+        "gauge",
+        "Configured probe interval of the target in seconds.",
+        &config
+            .endpoints()
+            .iter()
+            .map(|e| (format!("target=\"{}\"", e.host()), e.interval().to_string()))
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_auth_permanently_failed",
+        "gauge",
+        "Whether probing of the target is backed off due to rejected credentials.",
+        &states
+            .iter()
+            .map(|s| {
+                (
+                    format!("target=\"{}\"", s.host),
+                    (s.auth_permanently_failed as u8).to_string(),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_auth_throttled",
+        "gauge",
+        "Whether authentication to the target was delayed by the rate limit.",
+        &states
+            .iter()
+            .map(|s| {
+                (
+                    format!("target=\"{}\"", s.host),
+                    (s.auth_throttled as u8).to_string(),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_client_rebuilds_total",
+        "counter",
+        "Number of times the HTTP client of the target was rebuilt after repeated errors.",
+        &states
+            .iter()
+            .map(|s| {
+                (
+                    format!("target=\"{}\"", s.host),
+                    s.client_rebuilds.to_string(),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_retry_budget_exhausted_total",
+        "counter",
+        "Number of probe cycles of the target that ran out of retries.",
+        &states
+            .iter()
+            .map(|s| {
+                (
+                    format!("target=\"{}\"", s.host),
+                    s.retry_budget_exhausted.to_string(),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_exporter_scrape_duration_seconds",
+        "gauge",
+        "Duration of the last probe of the target.",
+        &states
+            .iter()
+            .filter_map(|s| {
+                s.last_scrape_duration.map(|d| {
+                    (
+                        format!("target=\"{}\"", s.host),
+                        d.as_secs_f64().to_string(),
+                    )
+                })
+            })
+            .collect::<Vec<_>>(),
+    );
+    // Left alone by failed probes, so its age reveals a stalled collector
+    push_exporter_metric(
+        &mut self_body,
+        "padm_exporter_last_scrape_timestamp_seconds",
+        "gauge",
+        "Unix time of the last successful probe of the target.",
+        &states
+            .iter()
+            .filter_map(|s| {
+                s.scraped_at
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                    .map(|d| {
+                        (
+                            format!("target=\"{}\"", s.host),
+                            d.as_secs_f64().to_string(),
+                        )
+                    })
+            })
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_exporter_scrape_errors_total",
+        "counter",
+        "Number of failed probes of the target.",
+        &states
+            .iter()
+            .map(|s| {
+                (
+                    format!("target=\"{}\"", s.host),
+                    s.scrape_errors.to_string(),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_exporter_auth_failures_total",
+        "counter",
+        "Number of failed authentication attempts against the target.",
+        &states
+            .iter()
+            .map(|s| (format!("target=\"{}\"", s.host), s.auth_errors.to_string()))
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_samples_exposed",
+        "gauge",
+        "Number of device samples exposed for the target.",
+        &states
+            .iter()
+            .map(|s| {
+                (
+                    format!("target=\"{}\"", s.host),
+                    target_samples
+                        .get(s.host.as_str())
+                        .unwrap_or(&0)
+                        .to_string(),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_decode_duration_seconds",
+        "gauge",
+        "Time spent decoding the last successful API response of the target.",
+        &states
+            .iter()
+            .filter_map(|s| {
+                s.decode_duration.map(|d| {
+                    (
+                        format!("target=\"{}\"", s.host),
+                        d.as_secs_f64().to_string(),
+                    )
+                })
+            })
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_device_cert_expiry_timestamp_seconds",
+        "gauge",
+        "Expiry of the TLS certificate presented by the target, in seconds since the epoch.",
+        &states
+            .iter()
+            .filter_map(|s| {
+                s.cert_expiry
+                    .map(|t| (format!("target=\"{}\"", s.host), t.to_string()))
+            })
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_devices_discovered",
+        "gauge",
+        "Number of devices currently reporting on the target.",
+        &states
+            .iter()
+            .map(|s| {
+                (
+                    format!("target=\"{}\"", s.host),
+                    s.devices.len().to_string(),
+                )
+            })
+            .collect::<Vec<_>>(),
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_device_count_mismatch",
+        "gauge",
+        "Whether the number of devices on the target differs from the expected count.",
+        &states
+            .iter()
+            .filter(|s| s.last_success.is_some())
+            .filter_map(|s| {
+                s.expected_devices.map(|expected| {
+                    (
+                        format!("target=\"{}\"", s.host),
+                        ((s.devices.len() != expected) as u8).to_string(),
+                    )
+                })
+            })
+            .collect::<Vec<_>>(),
+    );
+    // Devices fronted by more than one card are only counted once
+    let fleet_up: HashSet<&str> = states
+        .iter()
+        .flat_map(|s| s.devices.iter().map(|d| d.name.as_str()))
+        .collect();
+    let fleet_total: HashSet<&str> = states
+        .iter()
+        .flat_map(|s| s.known_devices.iter().map(|d| d.as_str()))
+        .collect();
+    push_exporter_metric(
+        &mut self_body,
+        "padm_fleet_devices_up",
+        "gauge",
+        "Number of distinct devices currently reporting across all targets.",
+        &[(String::new(), fleet_up.len().to_string())],
+    );
+    push_exporter_metric(
+        &mut self_body,
+        "padm_fleet_devices_total",
+        "gauge",
+        "Number of distinct devices seen across all targets.",
+        &[(String::new(), fleet_total.len().to_string())],
+    );
+    let mut scrape_durations = Histogram::new(config.scrape_duration_buckets());
+    for state in states {
+        scrape_durations.merge(&state.scrape_durations);
+    }
+    push_exporter_histogram(
+        &mut self_body,
+        "padm_scrape_duration_seconds",
+        "Duration of probes across all targets.",
+        &scrape_durations,
+    );
+    Ok(Exposition {
+        body,
+        self_body,
+        device_samples,
+        ready: states
+            .iter()
+            .any(|s| s.successful_probes >= s.warmup_probes.max(1)),
+        enums: states
+            .iter()
+            .map(|s| (s.host.to_owned(), s.enums.to_owned()))
+            .collect(),
+    })
+}
 
-        device_arcs.push(arc);
+/// Append a metric about the exporter itself, given pre-formatted label sets and values
+fn push_exporter_metric(
+    body: &mut String,
+    name: &str,
+    mtype: &str,
+    help: &str,
+    samples: &[(String, String)],
+) {
+    body.push_str(format!("# HELP {} {}\n", name, help).as_str());
+    body.push_str(format!("# TYPE {} {}\n", name, mtype).as_str());
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            body.push_str(format!("{} {}\n", name, value).as_str());
+        } else {
+            body.push_str(format!("{}{{{}}} {}\n", name, labels, value).as_str());
+        }
     }
+}
 
-    loop {
-        thread::park();
+fn push_exporter_histogram(body: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    body.push_str(format!("# HELP {} {}\n", name, help).as_str());
+    body.push_str(format!("# TYPE {} histogram\n", name).as_str());
+    let mut cumulative = 0;
+    for (bound, count) in histogram.bounds.iter().zip(&histogram.counts) {
+        cumulative += count;
+        body.push_str(format!("{}_bucket{{le=\"{}\"}} {}\n", name, bound, cumulative).as_str());
+    }
+    body.push_str(format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, histogram.count).as_str());
+    body.push_str(format!("{}_sum {}\n", name, histogram.sum).as_str());
+    body.push_str(format!("{}_count {}\n", name, histogram.count).as_str());
+}
+
+/// Apply the settings shared by all clients, starting the scrape duration
+/// histogram over if its buckets changed as counts can't be moved between buckets
+fn apply_client_settings(state: &mut EndpointState, config: &config::Config) {
+    state.summary_log = config.probe_summary_log();
+    state.counter_reset_protection = config.counter_reset_protection();
+    let scrape_durations = Histogram::new(config.scrape_duration_buckets());
+    if state.scrape_durations.bounds != scrape_durations.bounds {
+        state.scrape_durations = scrape_durations;
+    }
+}
+
+/// Start probing an endpoint in a local task, returning the state it reports into
+fn spawn_client(
+    endpoint: &config::Endpoint,
+    config: &config::Config,
+    rotations: &Rotations,
+    updated: &Arc<Notify>,
+) -> Option<Arc<Mutex<EndpointState>>> {
+    let client = match PADMClient::new(endpoint) {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed creating client for {}: {:#}", endpoint.host(), e);
+            return None;
+        }
+    };
+
+    let mut state = EndpointState {
+        host: endpoint.host(),
+        expected_devices: endpoint.expected_devices(),
+        warmup_probes: endpoint.warmup_probes(),
+        ..Default::default()
+    };
+    apply_client_settings(&mut state, config);
+    let arc = Arc::new(Mutex::new(state));
+    let arc_clone = arc.clone();
+    let updated = updated.clone();
+    let endpoint = endpoint.clone();
+    // The client stops once its sender is dropped from the map
+    let (rotation_tx, rotation_rx) = mpsc::unbounded_channel();
+    rotations.lock().insert(endpoint.host(), rotation_tx);
+
+    // Clients aren't Send, so they all run on the thread driving the probe loop
+    tokio::task::spawn_local(async move {
+        let _task = TaskGuard::new();
+        client_run(client, endpoint, arc_clone, updated, rotation_rx).await
+    });
+
+    Some(arc)
+}
+
+pub async fn run(
+    mut config: config::Config,
+    exposition: Arc<Mutex<Exposition>>,
+    rotations: Rotations,
+    mut reloads: mpsc::UnboundedReceiver<config::Config>,
+) {
+    // Clients notify this whenever they have new results
+    let updated = Arc::new(Notify::new());
+
+    // Spawn client tasks
+    let mut clients: Vec<(config::Endpoint, Arc<Mutex<EndpointState>>)> = config
+        .endpoints()
+        .iter()
+        .filter_map(|endpoint| {
+            spawn_client(endpoint, &config, &rotations, &updated).map(|arc| (endpoint.clone(), arc))
+        })
+        .collect();
+
+    let mut pusher = Pusher::new(&config).map(Pusher::spawn);
 
-        let mut all_devices = Vec::new();
-        for arc in &device_arcs {
-            all_devices.append(&mut arc.lock().unwrap().to_owned());
+    loop {
+        tokio::select! {
+            _ = updated.notified() => (),
+            // Apply reloaded configs, leaving clients of unchanged endpoints running
+            Some(new_config) = reloads.recv() => {
+                let (kept, removed): (Vec<_>, Vec<_>) = clients
+                    .into_iter()
+                    .partition(|(endpoint, _)| new_config.endpoints().contains(endpoint));
+                for (endpoint, _) in removed {
+                    info!("Stopping client for {}", endpoint.host());
+                    rotations.lock().remove(&endpoint.host());
+                }
+                let mut kept: HashMap<String, _> = kept
+                    .into_iter()
+                    .map(|(endpoint, arc)| (endpoint.host(), (endpoint, arc)))
+                    .collect();
+                clients = Vec::new();
+                for endpoint in new_config.endpoints() {
+                    match kept.remove(&endpoint.host()) {
+                        Some(client) => {
+                            apply_client_settings(&mut client.1.lock(), &new_config);
+                            clients.push(client);
+                        }
+                        None => {
+                            info!("Starting client for {}", endpoint.host());
+                            if let Some(arc) =
+                                spawn_client(endpoint, &new_config, &rotations, &updated)
+                            {
+                                clients.push((endpoint.clone(), arc));
+                            }
+                        }
+                    }
+                }
+                // Replacing the sender stops the previous pusher once it is done
+                pusher = Pusher::new(&new_config).map(Pusher::spawn);
+                config = new_config;
+            }
         }
-        match format_output_from_devices(&all_devices) {
-            Ok(output) => *body.lock().unwrap() = output,
+
+        let states: Vec<EndpointState> = clients
+            .iter()
+            .map(|(_, arc)| arc.lock().to_owned())
+            .collect();
+        match format_output_from_states(&states, &config) {
+            Ok(output) => {
+                // Never waits on the Pushgateway, the push happens in its own task
+                if let Some(pusher) = &pusher {
+                    pusher.send_replace(format!("{}{}", output.body, output.self_body));
+                }
+                *exposition.lock() = output;
+            }
             Err(e) => error!("Failed formatting metrics output: {}", e),
         }
     }
 }
 
+/// Carry an offset forward whenever a counter goes backwards, as happens when a
+/// device reboots, so that the exposed counters stay monotonic
+fn guard_counters(devices: &mut [Device], offsets: &mut HashMap<String, (f64, f64)>) {
+    for device in devices {
+        for variable in &mut device.variables {
+            if variable.get("type") != "counter" {
+                continue;
+            }
+            let value = match variable.get("value").parse::<f64>() {
+                Ok(v) => v,
+                Err(_) => continue,
+            };
+            let mut labels: Vec<String> = variable
+                .labels()
+                .iter()
+                .flatten()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect();
+            labels.sort();
+            let key = format!(
+                "{}/{}/{}",
+                device.name,
+                variable.get("name"),
+                labels.join(",")
+            );
+
+            let (last, offset) = offsets.entry(key).or_insert((value, 0.0));
+            if value < *last {
+                debug!(
+                    "Counter {} of device {} went from {} to {}, assuming a reset",
+                    variable.get("name"),
+                    device.name,
+                    last,
+                    value
+                );
+                *offset += *last;
+            }
+            *last = value;
+            if *offset != 0.0 {
+                variable.set_value((value + *offset).to_string());
+            }
+        }
+    }
+}
+
+/// Labels to attach to every metric of the devices behind an endpoint
+fn endpoint_labels(endpoint: &config::Endpoint) -> HashMap<String, String> {
+    let mut labels = HashMap::new();
+    if endpoint.emit_target_label() {
+        labels.insert(String::from("target"), endpoint.host());
+    }
+    if endpoint.emit_instance_label() {
+        labels.insert(String::from("instance"), endpoint.instance());
+    }
+    labels
+}
+
+/// Longest a failing endpoint is left alone between probes
+const MAX_FAILURE_BACKOFF: u64 = 300;
+
+/// Seconds to wait before the next probe, doubling with every consecutive failure
+fn failure_backoff(interval: u64, consecutive_failures: u32) -> u64 {
+    if consecutive_failures <= 1 {
+        return interval;
+    }
+    let factor = 1u64
+        .checked_shl(consecutive_failures - 1)
+        .unwrap_or(u64::MAX);
+    interval
+        .saturating_mul(factor)
+        .min(MAX_FAILURE_BACKOFF.max(interval))
+}
+
 async fn client_run(
     client: PADMClient,
-    devices_arc: Arc<Mutex<Vec<Device>>>,
-    main_thread: std::thread::Thread,
+    endpoint: config::Endpoint,
+    state_arc: Arc<Mutex<EndpointState>>,
+    updated: Arc<Notify>,
+    mut rotations: mpsc::UnboundedReceiver<Rotation>,
 ) {
+    // Offsets are kept in memory only, so they start over when the exporter restarts
+    let mut counter_offsets: HashMap<String, (f64, f64)> = HashMap::new();
+    let mut consecutive_failures: u32 = 0;
     loop {
-        match get_devices_from(&client).await {
-            Ok(devices) => {
-                *devices_arc.lock().unwrap() = devices;
+        let start = Instant::now();
+        // Settings may change with a reload while the client keeps running
+        let (summary_log, counter_reset_protection) = {
+            let state = state_arc.lock();
+            (state.summary_log, state.counter_reset_protection)
+        };
+        client.begin_cycle();
+        match get_devices_from(&client, &endpoint).await {
+            Ok(result) => {
+                let mut devices = result.devices;
+                devices.retain(|d| endpoint.device_wanted(&d.name));
+                if summary_log {
+                    info!(
+                        "Probed {}: {} devices, {} metrics, {} unmapped in {:.3}s",
+                        client.host(),
+                        devices.len(),
+                        devices.iter().map(|d| d.variables.len()).sum::<usize>(),
+                        result.unmapped,
+                        start.elapsed().as_secs_f64()
+                    );
+                }
+                let labels = endpoint_labels(&endpoint);
+                for device in &mut devices {
+                    device.labels.extend(labels.to_owned());
+                }
+                if counter_reset_protection {
+                    guard_counters(&mut devices, &mut counter_offsets);
+                }
+                let mut state = state_arc.lock();
+                state.omit_device_label = false;
+                if endpoint.single_device_mode() {
+                    if devices.len() == 1 {
+                        state.omit_device_label = true;
+                    } else if devices.len() > 1 {
+                        warn!(
+                            "Endpoint {} is in single device mode but reported {} devices, keeping the device label",
+                            client.host(),
+                            devices.len()
+                        );
+                    }
+                }
+                state
+                    .known_devices
+                    .extend(devices.iter().map(|d| d.name.to_owned()));
+                state.devices = devices;
+                state.decode_duration = Some(result.decode_duration);
+                state.last_success = Some(Instant::now());
+                state.scraped_at = Some(SystemTime::now());
+                state.last_probe_ok = true;
+                state.enums = result.enums;
+                state.successful_probes = state.successful_probes.saturating_add(1);
+                consecutive_failures = 0;
             }
-            Err(e) => error!(
-                "Failed getting devices from client {}: {}",
-                &client.host(),
-                e
-            ),
+            Err(e) => {
+                consecutive_failures = consecutive_failures.saturating_add(1);
+                error!(
+                    "Failed getting devices from client {}: {}",
+                    &client.host(),
+                    e
+                );
+                // Keep serving the last known metrics only within the grace window
+                let mut state = state_arc.lock();
+                state.last_probe_ok = false;
+                state.scrape_errors += 1;
+                let grace = Duration::from_secs(endpoint.stale_grace_secs());
+                let max_age = Duration::from_secs(client.interval()) + grace;
+                if state.last_success.is_some_and(|t| t.elapsed() > max_age)
+                    && !state.devices.is_empty()
+                {
+                    warn!(
+                        "Dropping stale metrics for {} after {}s without a successful probe",
+                        client.host(),
+                        grace.as_secs()
+                    );
+                    state.devices.clear();
+                }
+            }
+        }
+        // Back off for much longer once the credentials have been rejected repeatedly
+        let auth_permanently_failed = client.auth_failures() >= endpoint.auth_failure_threshold();
+        {
+            let mut state = state_arc.lock();
+            state
+                .scrape_durations
+                .observe(start.elapsed().as_secs_f64());
+            state.last_scrape_duration = Some(start.elapsed());
+            state.auth_permanently_failed = auth_permanently_failed;
+            state.auth_throttled = client.auth_throttled();
+            state.client_rebuilds = client.rebuilds();
+            state.auth_errors = client.auth_errors();
+            state.retry_budget_exhausted = client.retry_budget_exhausted();
+            state.cert_expiry = client.cert_expiry();
+        }
+        updated.notify_one();
+        let interval = if auth_permanently_failed {
+            error!(
+                "Credentials for {} rejected {} times, retrying in {}s",
+                client.host(),
+                client.auth_failures(),
+                endpoint.auth_failure_backoff()
+            );
+            endpoint.auth_failure_backoff()
+        } else {
+            failure_backoff(client.interval(), consecutive_failures)
+        };
+        // Probe again right away after a credential rotation
+        tokio::select! {
+            _ = async_std::task::sleep(Duration::from_secs(interval)) => (),
+            rotation = rotations.recv() => match rotation {
+                Some(rotation) => {
+                    info!("Rotating credentials for {}", client.host());
+                    let result = client
+                        .rotate_credentials(rotation.username, rotation.password)
+                        .await
+                        .map_err(|e| e.to_string());
+                    let _ = rotation.result.send(result);
+                }
+                // The endpoint was removed from the config
+                None => return,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially() {
+        assert_eq!(failure_backoff(10, 0), 10);
+        assert_eq!(failure_backoff(10, 1), 10);
+        assert_eq!(failure_backoff(10, 2), 20);
+        assert_eq!(failure_backoff(10, 4), 80);
+        assert_eq!(failure_backoff(10, 6), MAX_FAILURE_BACKOFF);
+        assert_eq!(failure_backoff(10, u32::MAX), MAX_FAILURE_BACKOFF);
+    }
+
+    #[test]
+    fn never_backs_off_below_the_interval() {
+        assert_eq!(failure_backoff(600, 3), 600);
+    }
+
+    #[test]
+    fn observes_into_buckets() {
+        let mut histogram = Histogram::new(&[1.0, 0.1, f64::INFINITY, 0.5, 0.5]);
+        assert_eq!(histogram.bounds, vec![0.1, 0.5, 1.0]);
+        // On a bound, between bounds and above the last bound
+        histogram.observe(0.5);
+        histogram.observe(0.2);
+        histogram.observe(0.7);
+        histogram.observe(3.0);
+        assert_eq!(histogram.counts, vec![0, 2, 1]);
+        assert_eq!(histogram.count, 4);
+        assert_eq!(histogram.sum, 4.4);
+
+        let mut body = String::new();
+        push_exporter_histogram(
+            &mut body,
+            "padm_scrape_duration_seconds",
+            "Duration.",
+            &histogram,
+        );
+        assert!(body.contains("padm_scrape_duration_seconds_bucket{le=\"0.1\"} 0\n"));
+        assert!(body.contains("padm_scrape_duration_seconds_bucket{le=\"0.5\"} 2\n"));
+        assert!(body.contains("padm_scrape_duration_seconds_bucket{le=\"1\"} 3\n"));
+        assert!(body.contains("padm_scrape_duration_seconds_bucket{le=\"+Inf\"} 4\n"));
+    }
+
+    fn config(settings: &str) -> config::Config {
+        toml::from_str(&format!("ip = \"0.0.0.0\"\nendpoints = []\n{}", settings)).unwrap()
+    }
+
+    #[test]
+    fn applies_reloaded_client_settings() {
+        let mut state = EndpointState::default();
+        apply_client_settings(&mut state, &config("scrape_duration_buckets = [1.0]"));
+        state.scrape_durations.observe(0.5);
+        apply_client_settings(&mut state, &config("scrape_duration_buckets = [1.0]"));
+        assert_eq!(state.scrape_durations.count, 1);
+        assert!(!state.summary_log);
+
+        apply_client_settings(
+            &mut state,
+            &config("scrape_duration_buckets = [0.5, 2.0]\nprobe_summary_log = true"),
+        );
+        assert_eq!(state.scrape_durations.bounds, vec![0.5, 2.0]);
+        assert_eq!(state.scrape_durations.counts, vec![0, 0]);
+        assert_eq!(state.scrape_durations.count, 0);
+        assert!(state.summary_log);
+    }
+
+    #[test]
+    fn follows_pages() {
+        let first = serde_json::json!({ "data": [], "meta": { "page": 1, "total_pages": 2 } });
+        assert_eq!(
+            next_page(&first, "/api/variables").as_deref(),
+            Some("/api/variables?page=2")
+        );
+        let last = serde_json::json!({ "data": [], "meta": { "page": 2, "total_pages": 2 } });
+        assert_eq!(next_page(&last, "/api/variables?page=2"), None);
+
+        let linked = serde_json::json!({
+            "data": [],
+            "links": { "next": "https://10.0.0.100/api/variables?cursor=abc" },
+        });
+        assert_eq!(
+            next_page(&linked, "/api/variables").as_deref(),
+            Some("/api/variables?cursor=abc")
+        );
+        let relative = serde_json::json!({ "links": { "next": "/api/variables?page=3" } });
+        assert_eq!(
+            next_page(&relative, "/api/variables?page=2").as_deref(),
+            Some("/api/variables?page=3")
+        );
+        let unpaginated = serde_json::json!({ "data": [] });
+        assert_eq!(next_page(&unpaginated, "/api/variables"), None);
+    }
+
+    fn devices(variables: &[(&str, &str)]) -> Vec<Device> {
+        let data: Vec<serde_json::Value> = variables
+            .iter()
+            .map(|(label, value)| {
+                serde_json::json!({ "attributes": {
+                    "label": label,
+                    "value": value,
+                    "device_id": 1,
+                    "device_name": "pdu-1",
+                }})
+            })
+            .collect();
+        load_all_from(&data, "attributes").unwrap()
+    }
+
+    #[test]
+    fn keeps_counters_monotonic_across_resets() {
+        let mut offsets = HashMap::new();
+        let exposed: Vec<String> = ["100", "150", "5", "20", "3"]
+            .iter()
+            .map(|value| {
+                let mut probe = devices(&[("Energy (kWh)", value), ("Humidity (%)", "10")]);
+                guard_counters(&mut probe, &mut offsets);
+                // Gauges are left alone
+                assert_eq!(probe[0].variables[1].get("value"), "10");
+                probe[0].variables[0].get("value").to_string()
+            })
+            .collect();
+        assert_eq!(exposed, ["100", "150", "155", "170", "173"]);
+    }
+
+    /// Device metrics rendered from a single probe of a target
+    fn render(config: &config::Config, variables: &[(&str, &str)]) -> String {
+        let state = EndpointState {
+            host: String::from("192.0.2.1:443"),
+            devices: devices(variables),
+            last_probe_ok: true,
+            ..Default::default()
+        };
+        format_output_from_states(&[state], config).unwrap().body
+    }
+
+    #[test]
+    fn applies_na_policies() {
+        let na = [("Humidity (%)", "N/A")];
+        let sample = "padm_humidity_percent{device=\"pdu-1\"}";
+        assert!(!render(&config(""), &na).contains(sample));
+        assert!(!render(&config("na_policy = \"skip\""), &na).contains(sample));
+        assert!(render(&config("na_policy = \"zero\""), &na).contains(&format!("{} 0\n", sample)));
+        assert!(render(&config("na_policy = \"nan\""), &na).contains(&format!("{} NaN\n", sample)));
+
+        // Only the configured sentinels are affected
+        let custom = config("na_policy = \"zero\"\nna_values = [\"unsupported\"]");
+        assert!(!render(&custom, &na).contains(sample));
+        let unsupported = [("Humidity (%)", "unsupported")];
+        assert!(render(&custom, &unsupported).contains(&format!("{} 0\n", sample)));
+    }
+
+    #[test]
+    fn prefixes_device_body_metrics() {
+        let body = render(
+            &config("metric_prefix = \"cool_\"\nemit_metric_metadata = true"),
+            &[("Humidity (%)", "45%")],
+        );
+        assert!(body.contains("# TYPE cool_humidity_percent gauge\n"));
+        assert!(body.contains("# TYPE cool_probe_success gauge\n"));
+        assert!(body.contains("# TYPE cool_metric_metadata gauge\n"));
+        assert!(body.contains("cool_metric_metadata{name=\"cool_humidity_percent\","));
+        assert!(!body.contains("padm_"));
+    }
+
+    #[test]
+    fn counts_legacy_aliases_as_samples() {
+        for (settings, samples) in [("", 2), ("emit_legacy_aliases = true", 3)] {
+            let state = EndpointState {
+                host: String::from("192.0.2.1:443"),
+                devices: devices(&[("Set Point Temperature (C)", "21"), ("Humidity (%)", "45%")]),
+                ..Default::default()
+            };
+            let output = format_output_from_states(&[state], &config(settings)).unwrap();
+            assert_eq!(output.device_samples, samples);
+            assert!(output.self_body.contains(&format!(
+                "padm_samples_exposed{{target=\"192.0.2.1:443\"}} {}\n",
+                samples
+            )));
         }
-        main_thread.unpark();
-        async_std::task::sleep(Duration::from_secs(client.interval())).await;
+    }
+
+    #[test]
+    fn always_labels_device_type_when_enabled() {
+        let body = render(
+            &config("emit_device_type_label = true"),
+            &[("Humidity (%)", "45%")],
+        );
+        assert!(body.contains("padm_humidity_percent{device=\"pdu-1\",device_type=\"\"} 45\n"));
+        let body = render(&config(""), &[("Humidity (%)", "45%")]);
+        assert!(body.contains("padm_humidity_percent{device=\"pdu-1\"} 45\n"));
+    }
+
+    #[test]
+    fn flags_devices_in_maintenance() {
+        let config = config(
+            "metric_prefix = \"cool_\"\n\
+             suppress_maintenance_metrics = true\n\
+             maintenance_indicator = { metric = \"operating_mode\", label = \"mode\", equals = \"Off\" }",
+        );
+        let body = render(
+            &config,
+            &[("Operating Mode", "Off"), ("Humidity (%)", "45%")],
+        );
+        assert!(!body.contains("cool_humidity_percent"));
+        assert!(body
+            .contains("cool_device_in_maintenance{target=\"192.0.2.1:443\",device=\"pdu-1\"} 1\n"));
+
+        let body = render(
+            &config,
+            &[("Operating Mode", "Cooling"), ("Humidity (%)", "45%")],
+        );
+        assert!(body.contains("cool_humidity_percent"));
+        assert!(!body.contains("cool_device_in_maintenance{"));
     }
 }
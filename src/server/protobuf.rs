@@ -0,0 +1,479 @@
+/*
+* Re-encodes the rendered text exposition as delimited protobuf MetricFamily messages
+*/
+
+/// Content type of the delimited protobuf exposition format
+pub const CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+/// Whether an `Accept` header asks for the delimited protobuf format
+pub fn accepted(accept: &str) -> bool {
+    accept.split(',').any(|media| {
+        let media = media.to_ascii_lowercase();
+        media.contains("application/vnd.google.protobuf")
+            && media.contains("proto=io.prometheus.client.metricfamily")
+            && media.contains("encoding=delimited")
+    })
+}
+
+#[derive(Default)]
+struct Family {
+    name: String,
+    help: String,
+    mtype: String,
+    samples: Vec<Sample>,
+}
+
+struct Sample {
+    /// Full sample name, which for histograms carries a `_bucket`, `_sum` or `_count` suffix
+    name: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+    timestamp: Option<i64>,
+}
+
+/// Encode a text exposition, keeping families and samples in the same order
+pub fn encode(text: &str) -> Vec<u8> {
+    let mut families: Vec<Family> = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if let Some(rest) = line.strip_prefix("# HELP ") {
+            let (name, help) = rest.split_once(' ').unwrap_or((rest, ""));
+            family(&mut families, name).help = unescape(help);
+        } else if let Some(rest) = line.strip_prefix("# TYPE ") {
+            let (name, mtype) = rest.split_once(' ').unwrap_or((rest, ""));
+            family(&mut families, name).mtype = mtype.trim().to_string();
+        } else if !line.is_empty() && !line.starts_with('#') {
+            if let Some(sample) = parse_sample(line) {
+                // Histogram series belong to the family announced before them
+                let name = match families.last() {
+                    Some(f) if f.mtype == "histogram" && histogram_suffix(f, &sample).is_some() => {
+                        f.name.to_owned()
+                    }
+                    _ => sample.name.to_owned(),
+                };
+                family(&mut families, &name).samples.push(sample);
+            }
+        }
+    }
+
+    let mut out = Vec::new();
+    for family in &families {
+        let message = encode_family(family);
+        write_varint(&mut out, message.len() as u64);
+        out.extend(message);
+    }
+    out
+}
+
+/// The family being built for `name`, starting a new one unless it is the latest
+fn family<'a>(families: &'a mut Vec<Family>, name: &str) -> &'a mut Family {
+    if families.last().is_none_or(|f| f.name != name) {
+        families.push(Family {
+            name: name.to_string(),
+            ..Default::default()
+        });
+    }
+    families.last_mut().unwrap()
+}
+
+/// Suffix of a histogram sample of `family`, if that's what it is
+fn histogram_suffix<'a>(family: &Family, sample: &'a Sample) -> Option<&'a str> {
+    let suffix = sample.name.strip_prefix(family.name.as_str())?;
+    ["_bucket", "_sum", "_count"]
+        .contains(&suffix)
+        .then_some(suffix)
+}
+
+/// Split a sample line into its metric name, labels, value and optional timestamp
+fn parse_sample(line: &str) -> Option<Sample> {
+    let name_end = line.find(['{', ' '])?;
+    let name = line[..name_end].to_string();
+    let mut rest = &line[name_end..];
+
+    let mut labels = Vec::new();
+    if let Some(inner) = rest.strip_prefix('{') {
+        let mut chars = inner.char_indices();
+        let mut key = String::new();
+        let mut end = None;
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '}' => {
+                    end = Some(i + 1);
+                    break;
+                }
+                ',' | ' ' => (),
+                '=' => {
+                    chars.next(); // opening quote
+                    let mut value = String::new();
+                    while let Some((_, c)) = chars.next() {
+                        match c {
+                            '\\' => match chars.next() {
+                                Some((_, 'n')) => value.push('\n'),
+                                Some((_, c)) => value.push(c),
+                                None => (),
+                            },
+                            '"' => break,
+                            c => value.push(c),
+                        }
+                    }
+                    labels.push((std::mem::take(&mut key), value));
+                }
+                c => key.push(c),
+            }
+        }
+        rest = &inner[end?..];
+    }
+
+    let mut fields = rest.split_whitespace();
+    let value = fields.next()?.parse::<f64>().ok()?;
+    let timestamp = fields.next().and_then(|t| t.parse::<i64>().ok());
+    Some(Sample {
+        name,
+        labels,
+        value,
+        timestamp,
+    })
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("\\n", "\n").replace("\\\\", "\\")
+}
+
+/// MetricFamily message
+fn encode_family(family: &Family) -> Vec<u8> {
+    // Values of the MetricType enum and the Metric field holding each type's
+    // value; anything unsupported is sent untyped
+    let (mtype, value_field) = match family.mtype.as_str() {
+        "counter" => (0, 3),
+        "gauge" => (1, 2),
+        "histogram" => (4, 7),
+        _ => (3, 5),
+    };
+    let mut out = Vec::new();
+    write_bytes(&mut out, 1, family.name.as_bytes());
+    if !family.help.is_empty() {
+        write_bytes(&mut out, 2, family.help.as_bytes());
+    }
+    write_key(&mut out, 3, 0);
+    write_varint(&mut out, mtype);
+    let metrics = match mtype {
+        4 => encode_histograms(family, value_field),
+        _ => family
+            .samples
+            .iter()
+            .map(|sample| {
+                let mut value = Vec::new();
+                write_key(&mut value, 1, 1);
+                value.extend(sample.value.to_le_bytes());
+                encode_metric(&sample.labels, value_field, &value, sample.timestamp)
+            })
+            .collect(),
+    };
+    for metric in metrics {
+        write_bytes(&mut out, 4, &metric);
+    }
+    out
+}
+
+/// Metric messages of a histogram family, one per label set other than `le`
+fn encode_histograms(family: &Family, value_field: u32) -> Vec<Vec<u8>> {
+    #[derive(Default)]
+    struct Series {
+        count: u64,
+        sum: f64,
+        buckets: Vec<(f64, u64)>,
+        timestamp: Option<i64>,
+    }
+    let mut series: Vec<(Vec<(String, String)>, Series)> = Vec::new();
+    for sample in &family.samples {
+        let labels: Vec<(String, String)> = sample
+            .labels
+            .iter()
+            .filter(|(name, _)| name != "le")
+            .cloned()
+            .collect();
+        let i = match series.iter().position(|(l, _)| *l == labels) {
+            Some(i) => i,
+            None => {
+                series.push((labels, Series::default()));
+                series.len() - 1
+            }
+        };
+        let entry = &mut series[i].1;
+        entry.timestamp = entry.timestamp.or(sample.timestamp);
+        match histogram_suffix(family, sample) {
+            Some("_count") => entry.count = sample.value as u64,
+            Some("_sum") => entry.sum = sample.value,
+            // The +Inf bucket is implied by the sample count
+            Some("_bucket") => {
+                let bound = sample
+                    .labels
+                    .iter()
+                    .find(|(name, _)| name == "le")
+                    .and_then(|(_, le)| le.parse::<f64>().ok());
+                if let Some(bound) = bound.filter(|b| b.is_finite()) {
+                    entry.buckets.push((bound, sample.value as u64));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    series
+        .iter()
+        .map(|(labels, series)| {
+            let mut histogram = Vec::new();
+            write_key(&mut histogram, 1, 0);
+            write_varint(&mut histogram, series.count);
+            write_key(&mut histogram, 2, 1);
+            histogram.extend(series.sum.to_le_bytes());
+            for (bound, count) in &series.buckets {
+                let mut bucket = Vec::new();
+                write_key(&mut bucket, 1, 0);
+                write_varint(&mut bucket, *count);
+                write_key(&mut bucket, 2, 1);
+                bucket.extend(bound.to_le_bytes());
+                write_bytes(&mut histogram, 3, &bucket);
+            }
+            encode_metric(labels, value_field, &histogram, series.timestamp)
+        })
+        .collect()
+}
+
+/// Metric message holding the already encoded value message in `value_field`
+fn encode_metric(
+    labels: &[(String, String)],
+    value_field: u32,
+    value: &[u8],
+    timestamp: Option<i64>,
+) -> Vec<u8> {
+    let mut metric = Vec::new();
+    for (name, value) in labels {
+        let mut pair = Vec::new();
+        write_bytes(&mut pair, 1, name.as_bytes());
+        write_bytes(&mut pair, 2, value.as_bytes());
+        write_bytes(&mut metric, 1, &pair);
+    }
+    write_bytes(&mut metric, value_field, value);
+    if let Some(timestamp) = timestamp {
+        write_key(&mut metric, 6, 0);
+        write_varint(&mut metric, timestamp as u64);
+    }
+    metric
+}
+
+fn write_key(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    write_varint(out, ((field as u64) << 3) | wire_type as u64);
+}
+
+fn write_bytes(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_key(out, field, 2);
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decoded MetricFamily: name, help, type and metrics as (labels, value)
+    type Decoded = (String, String, u64, Vec<(Vec<(String, String)>, Value)>);
+
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Plain(f64),
+        Histogram(u64, f64, Vec<(f64, u64)>),
+    }
+
+    fn read_varint(bytes: &[u8], pos: &mut usize) -> u64 {
+        let mut value = 0;
+        let mut shift = 0;
+        loop {
+            let byte = bytes[*pos];
+            *pos += 1;
+            value |= ((byte & 0x7f) as u64) << shift;
+            shift += 7;
+            if byte < 0x80 {
+                return value;
+            }
+        }
+    }
+
+    /// Fields of a message as (field number, varint or fixed64 value, bytes)
+    fn fields(bytes: &[u8]) -> Vec<(u64, u64, &[u8])> {
+        let mut pos = 0;
+        let mut out = Vec::new();
+        while pos < bytes.len() {
+            let key = read_varint(bytes, &mut pos);
+            match key & 7 {
+                0 => out.push((key >> 3, read_varint(bytes, &mut pos), &bytes[..0])),
+                1 => {
+                    let raw = u64::from_le_bytes(bytes[pos..pos + 8].try_into().unwrap());
+                    pos += 8;
+                    out.push((key >> 3, raw, &bytes[..0]));
+                }
+                2 => {
+                    let len = read_varint(bytes, &mut pos) as usize;
+                    out.push((key >> 3, 0, &bytes[pos..pos + len]));
+                    pos += len;
+                }
+                t => panic!("unexpected wire type {}", t),
+            }
+        }
+        out
+    }
+
+    fn text(bytes: &[u8]) -> String {
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn decode(bytes: &[u8]) -> Vec<Decoded> {
+        let mut families = Vec::new();
+        let mut pos = 0;
+        while pos < bytes.len() {
+            let len = read_varint(bytes, &mut pos) as usize;
+            let mut family = (String::new(), String::new(), 0, Vec::new());
+            for (field, value, data) in fields(&bytes[pos..pos + len]) {
+                match field {
+                    1 => family.0 = text(data),
+                    2 => family.1 = text(data),
+                    3 => family.2 = value,
+                    4 => {
+                        let mut labels = Vec::new();
+                        let mut metric_value = None;
+                        for (field, _, data) in fields(data) {
+                            match field {
+                                1 => {
+                                    let pair = fields(data);
+                                    labels.push((text(pair[0].2), text(pair[1].2)));
+                                }
+                                2 | 3 | 5 => {
+                                    metric_value =
+                                        Some(Value::Plain(f64::from_bits(fields(data)[0].1)))
+                                }
+                                7 => {
+                                    let histogram = fields(data);
+                                    let buckets = histogram
+                                        .iter()
+                                        .filter(|(field, _, _)| *field == 3)
+                                        .map(|(_, _, bucket)| {
+                                            let bucket = fields(bucket);
+                                            (f64::from_bits(bucket[1].1), bucket[0].1)
+                                        })
+                                        .collect();
+                                    metric_value = Some(Value::Histogram(
+                                        histogram[0].1,
+                                        f64::from_bits(histogram[1].1),
+                                        buckets,
+                                    ));
+                                }
+                                _ => (),
+                            }
+                        }
+                        family.3.push((labels, metric_value.unwrap()));
+                    }
+                    _ => (),
+                }
+            }
+            families.push(family);
+            pos += len;
+        }
+        families
+    }
+
+    fn labels(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn accepts_only_delimited_protobuf() {
+        assert!(accepted("application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=delimited;q=0.7,text/plain;version=0.0.4;q=0.3"));
+        assert!(!accepted("text/plain;version=0.0.4"));
+        assert!(!accepted(
+            "application/vnd.google.protobuf;proto=io.prometheus.client.MetricFamily;encoding=text"
+        ));
+    }
+
+    #[test]
+    fn round_trips_families() {
+        let exposition = "\
+# HELP padm_temperature_celsius Current \\ detected temperature.
+# TYPE padm_temperature_celsius gauge
+padm_temperature_celsius{device=\"cool-1\",target=\"a\"} 23.5
+padm_temperature_celsius{device=\"say \\\"hi\\\", world\",target=\"a\"} -1 1685016000000
+# HELP padm_scrape_errors_total Number of failed probes.
+# TYPE padm_scrape_errors_total counter
+padm_scrape_errors_total 3
+# HELP padm_empty Nothing reported.
+# TYPE padm_empty gauge
+padm_untyped{x=\"y\"} NaN
+";
+        let families = decode(&encode(exposition));
+        assert_eq!(families.len(), 4);
+
+        let (name, help, mtype, metrics) = &families[0];
+        assert_eq!(name, "padm_temperature_celsius");
+        assert_eq!(help, "Current \\ detected temperature.");
+        assert_eq!(*mtype, 1);
+        assert_eq!(
+            metrics[0],
+            (
+                labels(&[("device", "cool-1"), ("target", "a")]),
+                Value::Plain(23.5)
+            )
+        );
+        assert_eq!(
+            metrics[1],
+            (
+                labels(&[("device", "say \"hi\", world"), ("target", "a")]),
+                Value::Plain(-1.0)
+            )
+        );
+
+        assert_eq!(families[1].0, "padm_scrape_errors_total");
+        assert_eq!(families[1].2, 0);
+        assert_eq!(families[1].3, vec![(vec![], Value::Plain(3.0))]);
+
+        // Families without samples are kept, like in the text output
+        assert_eq!(families[2].0, "padm_empty");
+        assert!(families[2].3.is_empty());
+
+        assert_eq!(families[3].0, "padm_untyped");
+        assert_eq!(families[3].2, 3);
+        assert!(matches!(families[3].3[0].1, Value::Plain(v) if v.is_nan()));
+    }
+
+    #[test]
+    fn groups_histogram_series() {
+        let exposition = "\
+# HELP padm_scrape_duration_seconds Duration of probes.
+# TYPE padm_scrape_duration_seconds histogram
+padm_scrape_duration_seconds_bucket{le=\"0.1\"} 1
+padm_scrape_duration_seconds_bucket{le=\"1\"} 3
+padm_scrape_duration_seconds_bucket{le=\"+Inf\"} 4
+padm_scrape_duration_seconds_sum 2.5
+padm_scrape_duration_seconds_count 4
+";
+        let families = decode(&encode(exposition));
+        assert_eq!(families.len(), 1);
+        let (name, _, mtype, metrics) = &families[0];
+        assert_eq!(name, "padm_scrape_duration_seconds");
+        assert_eq!(*mtype, 4);
+        assert_eq!(
+            metrics,
+            &vec![(vec![], Value::Histogram(4, 2.5, vec![(0.1, 1), (1.0, 3)]))]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+use log::{debug, error};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+use crate::config;
+
+/// Number of failed pushes to the Pushgateway
+pub static PUSH_FAILURES: AtomicU64 = AtomicU64::new(0);
+
+/*
+* Pushes the rendered metrics to a Prometheus Pushgateway
+*/
+pub struct Pusher {
+    client: reqwest::Client,
+    url: String,
+    interval: Duration,
+    last_push: Option<Instant>,
+}
+impl Pusher {
+    /// Create a pusher if a Pushgateway is configured
+    pub fn new(config: &config::Config) -> Option<Pusher> {
+        let base = config.pushgateway_url()?;
+        let url = match push_url(
+            base,
+            config.pushgateway_job(),
+            config.pushgateway_instance(),
+        ) {
+            Ok(url) => url,
+            Err(e) => {
+                error!("Invalid Pushgateway URL {}: {}", base, e);
+                return None;
+            }
+        };
+
+        let client = match reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.pushgateway_timeout()))
+            .build()
+        {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed creating Pushgateway client: {}", e);
+                return None;
+            }
+        };
+
+        Some(Pusher {
+            client,
+            url,
+            interval: Duration::from_secs(config.pushgateway_interval()),
+            last_push: None,
+        })
+    }
+    /// Push in a task of its own so a slow Pushgateway never holds up scrapes,
+    /// returning where to send the latest rendered metrics
+    pub fn spawn(mut self) -> watch::Sender<String> {
+        let (tx, mut rx) = watch::channel(String::new());
+        tokio::spawn(async move {
+            // Only the latest metrics matter, anything rendered during a push is skipped
+            while rx.changed().await.is_ok() {
+                let body = rx.borrow_and_update().to_owned();
+                self.push(&body).await;
+            }
+        });
+        tx
+    }
+    /// Replace the metrics of our grouping key, unless we pushed too recently
+    async fn push(&mut self, body: &str) {
+        if self.last_push.is_some_and(|t| t.elapsed() < self.interval) {
+            return;
+        }
+        self.last_push = Some(Instant::now());
+
+        let response = self
+            .client
+            .put(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(strip_timestamps(body))
+            .send()
+            .await
+            .and_then(|r| r.error_for_status());
+        match response {
+            Ok(_) => debug!("Pushed metrics to {}", self.url),
+            Err(e) => {
+                PUSH_FAILURES.fetch_add(1, Ordering::Relaxed);
+                error!("Failed pushing metrics to {}: {}", self.url, e);
+            }
+        }
+    }
+}
+
+/// URL of our grouping key below the Pushgateway at `base`. Label values are
+/// percent-encoded, and those the Pushgateway can't take in a path segment
+/// (empty or containing a slash) are base64-encoded.
+fn push_url(base: &str, job: &str, instance: Option<&str>) -> Result<String, String> {
+    let mut url = reqwest::Url::parse(base).map_err(|e| e.to_string())?;
+    {
+        let mut segments = url
+            .path_segments_mut()
+            .map_err(|_| String::from("not a base URL"))?;
+        segments.pop_if_empty().push("metrics");
+        for (label, value) in [("job", Some(job)), ("instance", instance)] {
+            let value = match value {
+                Some(v) => v,
+                None => continue,
+            };
+            match value.is_empty() || value.contains('/') {
+                true => segments.extend([format!("{}@base64", label), base64_url(value)]),
+                false => segments.extend([label, value]),
+            };
+        }
+    }
+    Ok(url.to_string())
+}
+
+/// URL-safe base64, as the Pushgateway accepts for label values. The empty
+/// value is written as a lone padding character.
+fn base64_url(value: &str) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+    if value.is_empty() {
+        return String::from("=");
+    }
+    let mut encoded = String::new();
+    for chunk in value.as_bytes().chunks(3) {
+        let bits = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |acc, (i, b)| acc | (*b as u32) << (16 - 8 * i));
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+/// The Pushgateway rejects pushed samples with timestamps, so drop them
+fn strip_timestamps(body: &str) -> String {
+    let mut stripped = String::with_capacity(body.len());
+    for line in body.lines() {
+        if line.is_empty() || line.starts_with('#') {
+            stripped.push_str(line);
+            stripped.push('\n');
+            continue;
+        }
+        // Label values may contain spaces, so only split after the label set
+        let series_end = match line.find('{') {
+            Some(start) => closing_brace(line, start).map(|end| end + 1),
+            None => line.find(' '),
+        };
+        let (series, rest) = match series_end {
+            Some(end) => line.split_at(end),
+            None => (line, ""),
+        };
+        stripped.push_str(series);
+        if let Some(value) = rest.split_whitespace().next() {
+            stripped.push(' ');
+            stripped.push_str(value);
+        }
+        stripped.push('\n');
+    }
+    stripped
+}
+
+/// Index of the brace closing the label set opened at `start`, skipping over
+/// quoted label values
+fn closing_brace(line: &str, start: usize) -> Option<usize> {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line[start..].char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            '}' if !quoted => return Some(start + i),
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_grouping_key() {
+        assert_eq!(
+            push_url("http://pg:9091", "padm", Some("exporter-1")).unwrap(),
+            "http://pg:9091/metrics/job/padm/instance/exporter-1"
+        );
+        assert_eq!(
+            push_url("http://pg:9091/prefix/", "padm exporter", Some("x?y#z")).unwrap(),
+            "http://pg:9091/prefix/metrics/job/padm%20exporter/instance/x%3Fy%23z"
+        );
+        assert_eq!(
+            push_url("http://pg:9091", "padm/a", Some("")).unwrap(),
+            "http://pg:9091/metrics/job@base64/cGFkbS9h/instance@base64/="
+        );
+        assert!(push_url("pg:9091", "padm", None).is_err());
+    }
+
+    #[test]
+    fn encodes_base64() {
+        assert_eq!(base64_url("f"), "Zg");
+        assert_eq!(base64_url("fo"), "Zm8");
+        assert_eq!(base64_url("foo"), "Zm9v");
+        assert_eq!(base64_url("/var/tmp"), "L3Zhci90bXA");
+    }
+
+    #[test]
+    fn strips_timestamps() {
+        let body = "# HELP padm_humidity_percent Humidity.\n\
+                    # TYPE padm_humidity_percent gauge\n\
+                    padm_humidity_percent{device=\"pdu 1\",note=\"a} \\\" b\"} 45 1700000000000\n\
+                    padm_humidity_percent{device=\"pdu 2\"} 40\n\
+                    padm_push_failures_total 0 1700000000000\n";
+        assert_eq!(
+            strip_timestamps(body),
+            "# HELP padm_humidity_percent Humidity.\n\
+             # TYPE padm_humidity_percent gauge\n\
+             padm_humidity_percent{device=\"pdu 1\",note=\"a} \\\" b\"} 45\n\
+             padm_humidity_percent{device=\"pdu 2\"} 40\n\
+             padm_push_failures_total 0\n"
+        );
+    }
+}
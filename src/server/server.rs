@@ -1,51 +1,557 @@
 use actix_web::{
+    http::header,
     web::{self, Data},
-    App, HttpResponse, HttpRequest, HttpServer,
+    App, HttpResponse, HttpResponseBuilder, HttpRequest, HttpServer,
 };
-use log::debug;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
-use tokio::runtime::Runtime;
+use log::{debug, error, info, warn};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio::task::LocalSet;
 
 use crate::config;
 use crate::server;
+use crate::server::probe::{Exposition, Rotation, Rotations};
 
-async fn index(request: HttpRequest, body_mutex: Data<Arc<Mutex<String>>>) -> HttpResponse {
+/// Config as of the last successful load, replaced on every reload
+type LiveConfig = Arc<Mutex<config::Config>>;
+
+/// Respond with an exposition as protobuf if the client asks for it, as text otherwise
+fn exposition_response(
+    request: &HttpRequest,
+    mut response: HttpResponseBuilder,
+    body: String,
+) -> HttpResponse {
+    let accept = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    match server::protobuf::accepted(accept) {
+        true => response
+            .content_type(server::protobuf::CONTENT_TYPE)
+            .body(server::protobuf::encode(&body)),
+        false => response
+            .content_type("text/plain; version=0.0.4; charset=utf-8")
+            .body(body),
+    }
+}
+
+async fn index(
+    request: HttpRequest,
+    exposition: Data<Arc<Mutex<Exposition>>>,
+    config: Data<LiveConfig>,
+) -> HttpResponse {
+    let (scrape_timeout, empty_as_204, connection_close) = {
+        let config = config.lock();
+        (
+            config.scrape_timeout(),
+            config.empty_as_204(),
+            config.connection_close(),
+        )
+    };
     let peer_addr = request.peer_addr();
     match peer_addr {
         Some(addr) => debug!("Connection opened from {}", addr),
         None => debug!("Connection opened from unknown"),
     }
-    // Wait until we have data
-    if (*body_mutex.lock().unwrap()).is_empty() {
-        async_std::task::sleep(Duration::from_millis(1000)).await;
+    // Wait until we have data, but not for longer than the scrape timeout
+    let deadline = Instant::now() + Duration::from_secs(scrape_timeout);
+    while exposition.lock().body.is_empty() {
+        if Instant::now() >= deadline {
+            return HttpResponse::GatewayTimeout().body("No probe has completed yet\n");
+        }
+        async_std::task::sleep(Duration::from_millis(100)).await;
+    }
+    let exposition = exposition.lock();
+    // Optionally signal that no endpoint yielded any metrics
+    let empty = empty_as_204 && exposition.device_samples == 0;
+    let mut response = if empty {
+        HttpResponse::NoContent()
+    } else {
+        HttpResponse::Ok()
+    };
+    // Don't let scrapers hold connections open if asked
+    if connection_close {
+        response.force_close();
+    }
+    if empty {
+        return response.finish();
+    }
+    // Exporter metrics are served on /metrics instead
+    exposition_response(&request, response, exposition.body.to_string())
+}
+
+async fn metrics(request: HttpRequest, exposition: Data<Arc<Mutex<Exposition>>>) -> HttpResponse {
+    exposition_response(
+        &request,
+        HttpResponse::Ok(),
+        exposition.lock().self_body.to_string(),
+    )
+}
+
+/// Whether the exporter is alive, regardless of what its targets report
+async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().body("ok\n")
+}
+
+/// Reject anything but GET on read-only routes
+async fn method_not_allowed() -> HttpResponse {
+    HttpResponse::MethodNotAllowed()
+        .insert_header((actix_web::http::header::ALLOW, "GET"))
+        .finish()
+}
+
+/// Whether the exporter has data to serve and isn't being drained
+async fn readyz(
+    exposition: Data<Arc<Mutex<Exposition>>>,
+    draining: Data<AtomicBool>,
+) -> HttpResponse {
+    if draining.load(Ordering::Relaxed) {
+        return HttpResponse::ServiceUnavailable().body("draining\n");
+    }
+    match exposition.lock().ready {
+        true => HttpResponse::Ok().body("ready\n"),
+        false => HttpResponse::ServiceUnavailable().body("not ready\n"),
+    }
+}
+
+/// Whether a request carries the configured admin token
+fn admin_authorized(request: &HttpRequest, token: &str) -> bool {
+    request
+        .headers()
+        .get(actix_web::http::header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .is_some_and(|t| t == token)
+}
+
+/// Report not-ready from now on so load balancers move traffic away before shutdown
+async fn drain(
+    request: HttpRequest,
+    draining: Data<AtomicBool>,
+    config: Data<LiveConfig>,
+) -> HttpResponse {
+    let token = config.lock().admin_token().map(|t| t.to_string());
+    match token.as_deref() {
+        Some(token) if admin_authorized(&request, token) => (),
+        _ => return HttpResponse::Unauthorized().finish(),
+    }
+    if !draining.swap(true, Ordering::Relaxed) {
+        info!("Draining, /readyz will report not ready until shutdown");
+    }
+    HttpResponse::Ok().body("draining\n")
+}
+
+#[derive(Deserialize)]
+struct RotateQuery {
+    target: String,
+}
+
+#[derive(Deserialize)]
+struct Credentials {
+    username: String,
+    password: String,
+}
+
+/// Replace the credentials of a target in memory and report whether they work.
+/// Without a body, the credentials are re-read from the files of the target.
+async fn rotate(
+    request: HttpRequest,
+    query: web::Query<RotateQuery>,
+    body: web::Bytes,
+    rotations: Data<Rotations>,
+    config: Data<LiveConfig>,
+) -> HttpResponse {
+    let token = config.lock().admin_token().map(|t| t.to_string());
+    match token.as_deref() {
+        Some(token) if admin_authorized(&request, token) => (),
+        _ => return HttpResponse::Unauthorized().finish(),
+    }
+    let sender = match rotations.lock().get(&query.target) {
+        Some(sender) => sender.clone(),
+        None => return HttpResponse::NotFound().body(format!("Unknown target {}\n", query.target)),
+    };
+    let credentials = if body.is_empty() {
+        let reread = config
+            .lock()
+            .endpoints()
+            .iter()
+            .find(|e| e.host() == query.target)
+            .map(|e| e.reread_credentials());
+        match reread {
+            Some(Ok((username, password))) => Credentials { username, password },
+            Some(Err(e)) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                return HttpResponse::BadRequest().body(format!("{}\n", e))
+            }
+            Some(Err(e)) => return HttpResponse::InternalServerError().body(format!("{}\n", e)),
+            None => {
+                return HttpResponse::NotFound().body(format!("Unknown target {}\n", query.target))
+            }
+        }
+    } else {
+        match serde_json::from_slice::<Credentials>(&body) {
+            Ok(credentials) => credentials,
+            Err(e) => {
+                return HttpResponse::BadRequest().body(format!("Invalid credentials: {}\n", e))
+            }
+        }
+    };
+    let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+    let rotation = Rotation {
+        username: credentials.username,
+        password: credentials.password,
+        result: result_tx,
+    };
+    if sender.send(rotation).is_err() {
+        return HttpResponse::ServiceUnavailable().body("Client is not running\n");
+    }
+    match result_rx.await {
+        Ok(Ok(())) => HttpResponse::Ok().body("authenticated\n"),
+        Ok(Err(e)) => HttpResponse::BadGateway().body(format!("Authentication failed: {}\n", e)),
+        Err(_) => HttpResponse::ServiceUnavailable().body("Client is not running\n"),
+    }
+}
+
+/// Capture a CPU profile for a number of seconds and return it as a flamegraph
+#[cfg(feature = "profiling")]
+async fn profile(query: web::Query<std::collections::HashMap<String, String>>) -> HttpResponse {
+    let seconds = query
+        .get("seconds")
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or(10)
+        .clamp(1, 60);
+    let guard = match pprof::ProfilerGuardBuilder::default()
+        .frequency(100)
+        .build()
+    {
+        Ok(guard) => guard,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+    async_std::task::sleep(Duration::from_secs(seconds)).await;
+
+    let mut body = Vec::new();
+    match guard.report().build() {
+        Ok(report) => match report.flamegraph(&mut body) {
+            Ok(_) => HttpResponse::Ok().content_type("image/svg+xml").body(body),
+            Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+        },
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct EnumsQuery {
+    target: String,
+}
+
+/// List every value the enum variables of a target have been seen to take
+async fn enums(
+    query: web::Query<EnumsQuery>,
+    exposition: Data<Arc<Mutex<Exposition>>>,
+) -> HttpResponse {
+    match exposition.lock().enums.get(&query.target) {
+        Some(enums) => HttpResponse::Ok().json(enums),
+        None => HttpResponse::NotFound().body(format!("Unknown target {}\n", query.target)),
+    }
+}
+
+/// Register the routes of the listener serving exporter metrics
+fn metrics_routes(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/metrics")
+            .route(web::get().to(metrics))
+            .default_service(web::to(method_not_allowed)),
+    )
+    .service(
+        web::resource("/healthz")
+            .route(web::get().to(healthz))
+            .default_service(web::to(method_not_allowed)),
+    );
+}
+
+/// Register the routes of the main listener, which only serves exporter
+/// metrics when they don't have a listener of their own
+fn main_routes(cfg: &mut web::ServiceConfig, separate_metrics: bool, admin_endpoints: bool) {
+    cfg.service(
+        web::resource("/padm")
+            .route(web::get().to(index))
+            .default_service(web::to(method_not_allowed)),
+    )
+    .service(
+        web::resource("/readyz")
+            .route(web::get().to(readyz))
+            .default_service(web::to(method_not_allowed)),
+    );
+    if separate_metrics {
+        cfg.service(
+            web::resource("/healthz")
+                .route(web::get().to(healthz))
+                .default_service(web::to(method_not_allowed)),
+        );
+    } else {
+        metrics_routes(cfg);
+    }
+    if admin_endpoints {
+        cfg.route("/admin/drain", web::post().to(drain))
+            .route("/admin/rotate", web::post().to(rotate));
+    }
+}
+
+/// Register debugging routes when they are both built in and enabled
+fn debug_routes(cfg: &mut web::ServiceConfig, enabled: bool) {
+    if !enabled {
+        return;
     }
-    HttpResponse::Ok().body((*body_mutex.lock().unwrap()).to_string())
+    cfg.route("/padm/enums", web::get().to(enums));
+    #[cfg(feature = "profiling")]
+    cfg.route("/debug/pprof/profile", web::get().to(profile));
 }
 
-pub async fn run(config: config::Config) -> std::io::Result<()> {
-    // Create global body reference
-    let body_mutex = Arc::new(Mutex::new(String::new()));
-    let body_mutex_clone = body_mutex.clone();
+/// Settings only read at startup, whose changes a reload can't apply
+fn restart_required(old: &config::Config, new: &config::Config) -> Vec<&'static str> {
+    let mut changed = Vec::new();
+    if old.bind_address() != new.bind_address() {
+        changed.push("ip/port");
+    }
+    if old.metrics_bind_address() != new.metrics_bind_address() {
+        changed.push("metrics_port");
+    }
+    if old.enable_debug_endpoints() != new.enable_debug_endpoints() {
+        changed.push("enable_debug_endpoints");
+    }
+    if old.admin_token().is_some() != new.admin_token().is_some() {
+        changed.push("admin_token being set");
+    }
+    changed
+}
+
+/// Reload the config file on SIGHUP, applying it to the HTTP handlers and
+/// handing it over to the probe loop
+async fn reload_on_hangup(
+    config_path: String,
+    live: LiveConfig,
+    reloads: mpsc::UnboundedSender<config::Config>,
+) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!(
+                "Failed listening for SIGHUP, config reloads are unavailable: {}",
+                e
+            );
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Reloading config from {}", config_path);
+        let config = match config::load_config_from_file(&config_path) {
+            Ok(config) => config,
+            Err(e) => {
+                error!("Failed reloading config, keeping the current one: {}", e);
+                continue;
+            }
+        };
+        if let Err(problems) = config.validate() {
+            error!("Invalid config, keeping the current one:\n{}", problems);
+            continue;
+        }
+        for warning in config.load_warnings() {
+            warn!("{}", warning);
+        }
+        for warning in config.register_extra_metrics() {
+            warn!("{}", warning);
+        }
+        let changed = restart_required(&live.lock(), &config);
+        if !changed.is_empty() {
+            warn!(
+                "Changes to {} only take effect after a restart",
+                changed.join(", ")
+            );
+        }
+        *live.lock() = config.clone();
+        if reloads.send(config).is_err() {
+            return;
+        }
+    }
+}
+
+pub async fn run(config: config::Config, config_path: String) -> std::io::Result<()> {
+    // Create global exposition reference
+    let exposition = Arc::new(Mutex::new(Exposition::default()));
+    let exposition_clone = exposition.clone();
+    let rotations: Rotations = Default::default();
+    let rotations_clone = rotations.clone();
     let bind_address = config.bind_address();
+    let metrics_bind_address = config.metrics_bind_address();
+    let separate_metrics = metrics_bind_address.is_some();
+    let live_config: LiveConfig = Arc::new(Mutex::new(config.clone()));
+    let config_data = Data::new(live_config.clone());
+    let debug_endpoints = config.enable_debug_endpoints();
+    let admin_endpoints = config.admin_token().is_some();
+    let draining = Data::new(AtomicBool::new(false));
+    let rotations = Data::new(rotations);
+    if debug_endpoints && cfg!(not(feature = "profiling")) {
+        warn!("This build has no profiling support, /debug/pprof/profile is unavailable");
+    }
+
+    // Spawn probe loop, whose clients run as local tasks on this runtime
+    let (reloads_tx, reloads_rx) = mpsc::unbounded_channel();
+    let local = LocalSet::new();
+    local.spawn_local(server::probe::run(
+        config,
+        exposition_clone,
+        rotations_clone,
+        reloads_rx,
+    ));
+    tokio::spawn(reload_on_hangup(config_path, live_config, reloads_tx));
 
-    // Spawn probe thread
-    thread::spawn(move || {
-        let rt = Runtime::new().unwrap();
-        rt.block_on(async move { server::probe::run(config, body_mutex_clone).await });
-        loop {
-            thread::park();
+    // Serve exporter metrics on a separate listener if asked
+    let metrics_server = match metrics_bind_address {
+        Some(metrics_bind_address) => {
+            let exposition = exposition.clone();
+            Some(
+                HttpServer::new(move || {
+                    App::new()
+                        .app_data(Data::new(exposition.clone()))
+                        .configure(metrics_routes)
+                })
+                .bind(metrics_bind_address)?
+                .run(),
+            )
         }
-    });
+        None => None,
+    };
 
     // Startup
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
-            .app_data(Data::new(body_mutex.clone()))
-            .route("/padm", web::get().to(index))
+            .app_data(Data::new(exposition.clone()))
+            .app_data(config_data.clone())
+            .app_data(draining.clone())
+            .app_data(rotations.clone())
+            .configure(|cfg| main_routes(cfg, separate_metrics, admin_endpoints))
+            .configure(|cfg| debug_routes(cfg, debug_endpoints))
     })
     .bind(bind_address)?
-    .run()
-    .await
+    .run();
+
+    local
+        .run_until(async move {
+            match metrics_server {
+                Some(metrics_server) => tokio::try_join!(server, metrics_server).map(|_| ()),
+                None => server.await,
+            }
+        })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, test};
+
+    async fn get_metrics(routes: impl FnOnce(&mut web::ServiceConfig)) -> StatusCode {
+        let exposition = Arc::new(Mutex::new(Exposition::default()));
+        let app =
+            test::init_service(App::new().app_data(Data::new(exposition)).configure(routes)).await;
+        let request = test::TestRequest::get().uri("/metrics").to_request();
+        test::call_service(&app, request).await.status()
+    }
+
+    #[actix_web::test]
+    async fn serves_exporter_metrics_on_one_listener() {
+        assert_eq!(get_metrics(metrics_routes).await, StatusCode::OK);
+        assert_eq!(
+            get_metrics(|cfg| main_routes(cfg, true, false)).await,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            get_metrics(|cfg| main_routes(cfg, false, false)).await,
+            StatusCode::OK
+        );
+    }
+
+    #[actix_web::test]
+    async fn rotates_credentials_from_body_or_files() {
+        let password_file =
+            std::env::temp_dir().join(format!("padm_exporter_rotate_{}", std::process::id()));
+        std::fs::write(&password_file, "from-file\n").unwrap();
+        let config: config::Config = toml::from_str(&format!(
+            "ip = \"0.0.0.0\"\n\
+             admin_token = \"token\"\n\
+             [[endpoints]]\n\
+             ip = \"192.0.2.1\"\n\
+             username = \"admin\"\n\
+             password_file = {:?}\n\
+             [[endpoints]]\n\
+             ip = \"192.0.2.2\"\n\
+             username = \"admin\"\n\
+             password = \"secret\"\n",
+            password_file.to_str().unwrap()
+        ))
+        .unwrap();
+
+        // Stand in for the clients, accepting any credentials they are handed
+        let rotations: Rotations = Default::default();
+        let (tx, mut rx) = mpsc::unbounded_channel::<Rotation>();
+        rotations
+            .lock()
+            .insert(String::from("192.0.2.1:443"), tx.clone());
+        rotations.lock().insert(String::from("192.0.2.2:443"), tx);
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        actix_web::rt::spawn(async move {
+            while let Some(rotation) = rx.recv().await {
+                log.lock().push((rotation.username, rotation.password));
+                let _ = rotation.result.send(Ok(()));
+            }
+        });
+
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(rotations))
+                .app_data(Data::new(Arc::new(Mutex::new(config))))
+                .configure(|cfg| main_routes(cfg, false, true)),
+        )
+        .await;
+        let rotate = |target: &str, body: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/admin/rotate?target={}", target))
+                .insert_header((header::AUTHORIZATION, "Bearer token"))
+                .set_payload(body.to_string())
+                .to_request()
+        };
+
+        let response = test::call_service(
+            &app,
+            rotate(
+                "192.0.2.1:443",
+                r#"{"username": "new", "password": "pass"}"#,
+            ),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = test::call_service(&app, rotate("192.0.2.1:443", "")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            *received.lock(),
+            [
+                (String::from("new"), String::from("pass")),
+                (String::from("admin"), String::from("from-file")),
+            ]
+        );
+
+        // Nothing to re-read without a password_file
+        let response = test::call_service(&app, rotate("192.0.2.2:443", "")).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        std::fs::remove_file(&password_file).unwrap();
+        let response = test::call_service(&app, rotate("192.0.2.1:443", "")).await;
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(received.lock().len(), 2);
+    }
 }